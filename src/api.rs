@@ -1,12 +1,23 @@
 use std::fmt;
+use std::io::{ self, Write };
+use std::borrow::Cow;
+use std::sync::RwLock;
+use std::thread::{ self, JoinHandle };
 use url::Url;
+use reqwest::header::{ HeaderMap, HeaderName, HeaderValue };
 use graphql_client::{ GraphQLQuery, Response };
+use serde::{ Serialize, Deserialize, de::DeserializeOwned };
 
-#[doc(hidden)]
+/// Errors from talking to the check-in server
 pub enum Error {
 	Network(reqwest::Error),
 	Message(&'static str),
 	GraphQL(Vec<graphql_client::Error>),
+	IO(io::Error),
+	/// The server rejected the auth token (e.g. because the session expired)
+	Unauthorized,
+	/// An account endpoint (`/api/user/*`) failed with this HTTP status and response body
+	Server { status: u16, body: String },
 }
 impl fmt::Debug for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -14,14 +25,25 @@ impl fmt::Debug for Error {
 			Error::Network(err) => write!(f, "{:?}", err),
 			Error::Message(s) => write!(f, "{}", s),
 			Error::GraphQL(err) => write!(f, "{:?}", err),
+			Error::IO(err) => write!(f, "{:?}", err),
+			Error::Unauthorized => write!(f, "Not logged in or session expired"),
+			Error::Server { status, body } => write!(f, "Server responded with HTTP {}: {}", status, body),
 		}
 	}
 }
+/// `Result` with this module's `Error` as the default error type
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
 impl From<reqwest::Error> for Error {
 	fn from(err: reqwest::Error) -> Error {
 		Error::Network(err)
 	}
 }
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::IO(err)
+	}
+}
 impl From<&'static str> for Error {
 	fn from(err: &'static str) -> Error {
 		Error::Message(err)
@@ -34,6 +56,7 @@ impl From<&'static str> for Error {
 	query_path = "api.graphql",
 	response_derives = "Debug",
 )]
+#[allow(dead_code)]
 struct UserSearch;
 
 #[derive(GraphQLQuery)]
@@ -44,6 +67,14 @@ struct UserSearch;
 )]
 struct UserGet;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+	schema_path = "schema.graphql",
+	query_path = "api.graphql",
+	response_derives = "Debug",
+)]
+struct UsersGet;
+
 #[derive(GraphQLQuery)]
 #[graphql(
 	schema_path = "schema.graphql",
@@ -52,6 +83,14 @@ struct UserGet;
 )]
 struct TagsGet;
 
+#[derive(GraphQLQuery)]
+#[graphql(
+	schema_path = "schema.graphql",
+	query_path = "api.graphql",
+	response_derives = "Debug",
+)]
+struct TagCounts;
+
 #[derive(GraphQLQuery)]
 #[graphql(
 	schema_path = "schema.graphql",
@@ -60,11 +99,154 @@ struct TagsGet;
 )]
 struct CheckInTag;
 pub type CheckInReturn = (bool, check_in_tag::UserData, check_in_tag::TagData);
+/// A team member's user ID and the result of checking them in, as returned by `CheckinAPI::check_in_team`
+pub type TeamCheckIn = (String, Result<CheckInReturn>);
+
+/// Registration information about a user as returned by `CheckinAPI::iter_users`
+pub type User = users_get::UserData;
+/// Registration information about a user as returned by `CheckinAPI::get_user`
+///
+/// Has the same fields as `User`
+pub type UserDetails = user_get::UserData;
+
+/// The result of `CheckinAPI::check_in_outcome`
+#[derive(Debug)]
+pub enum CheckInOutcome {
+	CheckedIn(Box<CheckInReturn>),
+	/// The user was already checked into the tag
+	///
+	/// Only reported for tags with `warnOnDuplicates` set. For other tags the server accepts repeat check-ins, so they
+	/// come back as `CheckedIn`.
+	AlreadyCheckedIn(Box<CheckInReturn>),
+	/// The user hasn't been accepted or hasn't confirmed their attendance
+	///
+	/// The server has no eligibility rules of its own, so eligibility is only known from the user data that comes back
+	/// with the check-in. **The check-in was still recorded on the server and isn't rolled back.** Use
+	/// `CheckinAPI::check_in_outcome_with`, which looks the user up first, to keep ineligible users from being
+	/// checked in.
+	NotAccepted,
+	/// No user has the scanned ID
+	UnknownUser,
+	UnknownTag,
+}
+
+/// Which users may be checked into a tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eligibility {
+	/// Accepted users whether or not they've confirmed (e.g. a registration desk where they confirm on the spot)
+	RequireAcceptedOnly,
+	/// Accepted users who have also confirmed their attendance
+	RequireAcceptedAndConfirmed,
+}
+
+impl Eligibility {
+	pub fn permits(self, accepted: bool, confirmed: bool) -> bool {
+		match self {
+			Eligibility::RequireAcceptedOnly => accepted,
+			Eligibility::RequireAcceptedAndConfirmed => accepted && confirmed,
+		}
+	}
+}
+
+/// The result of `CheckinAPI::check_in_with_override`
+#[derive(Debug)]
+pub struct OverrideCheckIn {
+	pub result: CheckInReturn,
+	/// Whether the user would have been turned away by `check_in`
+	pub overridden: bool,
+	/// The reason given for the override
+	pub reason: String,
+}
+
+impl CheckInOutcome {
+	fn from_result(result: CheckInReturn) -> Self {
+		if result.0 {
+			CheckInOutcome::CheckedIn(Box::new(result))
+		}
+		else {
+			CheckInOutcome::AlreadyCheckedIn(Box::new(result))
+		}
+	}
+
+	/// Turns outcomes other than `CheckedIn` and `AlreadyCheckedIn` into the errors that `CheckinAPI::check_in` returns
+	pub fn into_result(self) -> Result<CheckInReturn, Error> {
+		match self {
+			CheckInOutcome::CheckedIn(result) | CheckInOutcome::AlreadyCheckedIn(result) => Ok(*result),
+			CheckInOutcome::NotAccepted => Err("User not accepted and confirmed".into()),
+			CheckInOutcome::UnknownUser => Err("Invalid user ID on badge".into()),
+			CheckInOutcome::UnknownTag => Err("Unknown tag".into()),
+		}
+	}
+}
+
+/// Turns an unsuccessful response into an `Error::Server` with its status and body
+fn server_error(response: reqwest::blocking::Response) -> Error {
+	let status = response.status().as_u16();
+	let body = response.text().unwrap_or_default();
+	Error::Server { status, body }
+}
+
+/// A single check in or check out of a user at a tag
+#[derive(Debug, Clone)]
+pub struct CheckinRecord {
+	pub uuid: String,
+	pub name: String,
+	pub tag: String,
+	/// Whether this was a check in or a check out
+	pub checked_in: bool,
+	/// The ISO 8601 time the server sent
+	pub timestamp: String,
+	/// The account that did the check in / check out
+	pub operator: String,
+}
+
+#[cfg(feature = "chrono")]
+impl CheckinRecord {
+	/// Parses `timestamp`, failing if the server changed its format
+	pub fn timestamp_utc(&self) -> Result<chrono::DateTime<chrono::Utc>, Error> {
+		parse_timestamp(&self.timestamp)
+	}
+}
+
+/// Parses a time sent by the server (e.g. `checked_in_date` in check-in results)
+#[cfg(feature = "chrono")]
+pub fn parse_timestamp(value: &str) -> Result<chrono::DateTime<chrono::Utc>, Error> {
+	chrono::DateTime::parse_from_rfc3339(value)
+		.map(|timestamp| timestamp.with_timezone(&chrono::Utc))
+		.map_err(|_| Error::Message("Unexpected timestamp format from server"))
+}
 
+/// The result of looking up the user ID encoded on a badge
+#[derive(Debug)]
+pub struct BadgeVerification {
+	/// Whether the user ID belongs to a user on this check-in instance
+	pub known: bool,
+	pub accepted: bool,
+	pub confirmed: bool,
+	/// The user's name, if they are known
+	pub name: Option<String>,
+}
+
+/// A logged in connection to a check-in instance
+///
+/// `CheckinAPI` is `Send + Sync` and the underlying HTTP client pools connections, so a single instance can be shared
+/// between reader threads with an `Arc` instead of logging in once per thread
 pub struct CheckinAPI {
 	base_url: Url,
 	client: reqwest::blocking::Client,
 	auth_cookie: String,
+	allow_unconfirmed: bool,
+	headers: HeaderMap,
+	graphql_path: String,
+	login_path: String,
+	user_update_path: String,
+	json_token_login: bool,
+}
+
+/// The body of a login response from servers that return the auth token as JSON
+#[derive(Deserialize)]
+struct TokenResponse {
+	token: String,
 }
 
 /// An implementation of the [HackGT Check-In](https://github.com/HackGT/checkin2) API
@@ -73,17 +255,35 @@ impl CheckinAPI {
 	///
 	/// Note: this will block for a few seconds because the server has a high PBKDF2 iteration count by default
 	pub fn login(username: &str, password: &str, url: &str) -> Result<Self, Error> {
-		let client = reqwest::blocking::Client::new();
-		let base_url = Url::parse(url).expect("Invalid base URL configured");
+		let mut api = CheckinAPI::from_token(String::new(), url);
+		api.relogin(username, password)?;
+		Ok(api)
+	}
+
+	/// Same as `login` but runs on a new thread so the caller (e.g. a UI showing a spinner) isn't blocked
+	///
+	/// Poll `JoinHandle::is_finished` or join the handle to get the logged in instance
+	pub fn login_in_thread(username: &str, password: &str, url: &str) -> JoinHandle<Result<Self, Error>> {
+		let (username, password, url) = (username.to_owned(), password.to_owned(), url.to_owned());
+		thread::spawn(move || CheckinAPI::login(&username, &password, &url))
+	}
 
+	/// Log in again and replace the stored auth token, keeping every other setting on this instance
+	///
+	/// Blocks for a few seconds like `login`
+	pub fn relogin(&mut self, username: &str, password: &str) -> Result<(), Error> {
 		let params = [("username", username), ("password", password)];
-		let response = client.post(base_url.join("/api/user/login").unwrap())
+		let response = self.client.post(self.base_url.join(&self.login_path).unwrap())
+			.headers(self.headers.clone())
 			.form(&params)
 			.send()?;
 
-		if !response.status().is_success() {
+		if response.status() == reqwest::StatusCode::UNAUTHORIZED {
 			return Err("Invalid username or password".into());
 		}
+		if !response.status().is_success() {
+			return Err(server_error(response));
+		}
 
 		let cookies = response.headers().get_all(reqwest::header::SET_COOKIE);
 		let mut auth_token: Option<String> = None;
@@ -96,15 +296,22 @@ impl CheckinAPI {
 			}
 		}
 
+		if auth_token.is_none() && self.json_token_login {
+			let is_json = response.headers().get(reqwest::header::CONTENT_TYPE)
+				.and_then(|content_type| content_type.to_str().ok())
+				.is_some_and(|content_type| content_type.starts_with("application/json"));
+			if is_json {
+				let body: TokenResponse = response.json()?;
+				auth_token = Some(body.token);
+			}
+		}
+
 		match auth_token {
 			Some(mut token) => {
 				// Create a HTTP cookie header out of this token
 				token.insert_str(0, "auth=");
-				Ok(Self {
-					base_url,
-					client,
-					auth_cookie: token,
-				})
+				self.auth_cookie = token;
+				Ok(())
 			},
 			None => Err("No auth token set by server".into())
 		}
@@ -118,85 +325,242 @@ impl CheckinAPI {
 		let base_url = Url::parse(url).expect("Invalid base URL configured");
 		// Create a HTTP cookie header out of this token
 		auth_token.insert_str(0, "auth=");
-		Self { base_url, client, auth_cookie: auth_token }
+		Self {
+			base_url,
+			client,
+			auth_cookie: auth_token,
+			allow_unconfirmed: false,
+			headers: HeaderMap::new(),
+			graphql_path: "/graphql".to_owned(),
+			login_path: "/api/user/login".to_owned(),
+			user_update_path: "/api/user/update".to_owned(),
+			json_token_login: false,
+		}
+	}
+
+	/// Sets the path of the GraphQL endpoint (`/graphql` by default), e.g. `/api/graphql` behind a reverse proxy
+	///
+	/// Paths starting with `/` replace the base URL's path while relative paths are resolved against it
+	pub fn with_graphql_path(mut self, path: &str) -> Self {
+		self.graphql_path = path.to_owned();
+		self
+	}
+
+	/// Sets the path used by `relogin` to log in (`/api/user/login` by default)
+	pub fn with_login_path(mut self, path: &str) -> Self {
+		self.login_path = path.to_owned();
+		self
+	}
+
+	/// Sets the path used by `add_user` and `delete_user` (`/api/user/update` by default)
+	pub fn with_user_update_path(mut self, path: &str) -> Self {
+		self.user_update_path = path.to_owned();
+		self
+	}
+
+	/// Makes `relogin` fall back to reading the auth token from a JSON body (`{"token":"..."}`) when the server doesn't
+	/// set an `auth` cookie
+	///
+	/// Off by default since the standard check-in server always uses `Set-Cookie`
+	pub fn with_json_token_login(mut self, enabled: bool) -> Self {
+		self.json_token_login = enabled;
+		self
+	}
+
+	/// Sets the `User-Agent` sent with every request made from this instance (e.g. `hackgt-kiosk/1.2`)
+	///
+	/// Panics if the user agent isn't a valid header value
+	pub fn with_user_agent(self, user_agent: &str) -> Self {
+		self.with_header(reqwest::header::USER_AGENT.as_str(), user_agent)
+	}
+
+	/// Adds a header (e.g. `X-Device-Id`) that is sent with every request made from this instance
+	///
+	/// Setting the same header again replaces its value. Panics if the name or value aren't valid in an HTTP header.
+	pub fn with_header(mut self, name: &str, value: &str) -> Self {
+		let name = HeaderName::from_bytes(name.as_bytes()).expect("Invalid header name");
+		let value = HeaderValue::from_str(value).expect("Invalid header value");
+		self.headers.insert(name, value);
+		self
 	}
 
 	pub fn auth_token(&self) -> &str {
 		&self.auth_cookie[5..]
 	}
 
+	/// The server's current time, read from the `Date` header of a request to the API
+	///
+	/// Comparing this to the local clock gives the device's clock skew, which can be used to correct the times of
+	/// queued offline check-ins. Only accurate to the second.
+	#[cfg(feature = "chrono")]
+	pub fn server_time(&self) -> Result<chrono::DateTime<chrono::Utc>, Error> {
+		let response = self.client.head(self.base_url.join(&self.graphql_path).unwrap())
+			.headers(self.headers.clone())
+			.header(reqwest::header::COOKIE, self.auth_cookie.as_str())
+			.send()?;
+		let date = response.headers().get(reqwest::header::DATE)
+			.and_then(|date| date.to_str().ok())
+			.ok_or(Error::Message("Server did not send a Date header"))?;
+		chrono::DateTime::parse_from_rfc2822(date)
+			.map(|date| date.with_timezone(&chrono::Utc))
+			.map_err(|_| Error::Message("Unexpected Date header format from server"))
+	}
+
+	/// Allow checking in users who have been accepted but haven't confirmed their attendance yet
+	///
+	/// Off by default. Users who haven't been accepted are always rejected. Check the `confirmed` field of the returned
+	/// user information to tell these check-ins apart.
+	pub fn set_allow_unconfirmed(&mut self, allow_unconfirmed: bool) {
+		self.allow_unconfirmed = allow_unconfirmed;
+	}
+
+	/// Whether users who haven't confirmed their attendance can be checked in (see `set_allow_unconfirmed`)
+	pub fn allow_unconfirmed(&self) -> bool {
+		self.allow_unconfirmed
+	}
+
 	/// Creates a new user with the provided username / password combination
 	///
 	/// Can be used to provision sub-devices like with [checkin-embedded](https://github.com/HackGT/checkin-embedded)
 	pub fn add_user(&self, username: &str, password: &str) -> Result<(), Error> {
 		let params = [("username", username), ("password", password)];
-		let response = self.client.put(self.base_url.join("/api/user/update").unwrap())
+		let response = self.client.put(self.base_url.join(&self.user_update_path).unwrap())
+			.headers(self.headers.clone())
 			.header(reqwest::header::COOKIE, self.auth_cookie.as_str())
 			.form(&params)
 			.send()?;
 
-		if !response.status().is_success() {
-			Err("Account creation unsuccessful".into())
+		if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+			Err(Error::Unauthorized)
+		}
+		else if !response.status().is_success() {
+			Err(server_error(response))
 		}
 		else {
 			Ok(())
 		}
 	}
 
+	/// Creates a user for each username / password pair
+	///
+	/// Returns one result per account in order. A failed account (e.g. a duplicate username) doesn't stop the rest
+	/// from being created.
+	pub fn add_users(&self, accounts: &[(String, String)]) -> Vec<Result<(), Error>> {
+		accounts.iter()
+			.map(|(username, password)| self.add_user(username, password))
+			.collect()
+	}
+
 	pub fn delete_user(&self, username: &str) -> Result<(), Error> {
 		let params = [("username", username)];
-		let response = self.client.delete(self.base_url.join("/api/user/update").unwrap())
+		let response = self.client.delete(self.base_url.join(&self.user_update_path).unwrap())
+			.headers(self.headers.clone())
 			.header(reqwest::header::COOKIE, self.auth_cookie.as_str())
 			.form(&params)
 			.send()?;
 
-		if !response.status().is_success() {
-			Err("Account deletion unsuccessful".into())
+		if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+			Err(Error::Unauthorized)
+		}
+		else if !response.status().is_success() {
+			Err(server_error(response))
 		}
 		else {
 			Ok(())
 		}
 	}
 
-	fn checkin_action(&self, check_in: bool, uuid: &str, tag: &str) -> Result<CheckInReturn, Error> {
-		let body = CheckInTag::build_query(check_in_tag::Variables {
-			id: uuid.to_string(),
-			tag: tag.to_string(),
-			checkin: check_in,
-		});
+	/// Sends a GraphQL query to the check-in instance and returns its data
+	fn graphql<Q: GraphQLQuery>(&self, variables: Q::Variables) -> Result<Q::ResponseData, Error> {
+		self.post_graphql(&Q::build_query(variables))
+	}
 
-		let response: Response<check_in_tag::ResponseData> = self.client.post(self.base_url.join("/graphql").unwrap())
+	/// Sends a GraphQL query that this crate doesn't have a typed method for and deserializes its data into `T`
+	///
+	/// Uses the same endpoint and auth cookie as the typed methods. Errors returned by the server are given back as
+	/// `Error::GraphQL`.
+	pub fn raw_graphql<T: DeserializeOwned>(&self, query: &str, variables: serde_json::Value) -> Result<T, Error> {
+		self.post_graphql(&serde_json::json!({
+			"query": query,
+			"variables": variables,
+		}))
+	}
+
+	fn post_graphql<B: Serialize, T: DeserializeOwned>(&self, body: &B) -> Result<T, Error> {
+		let response = self.client.post(self.base_url.join(&self.graphql_path).unwrap())
+			.headers(self.headers.clone())
 			.header(reqwest::header::COOKIE, self.auth_cookie.as_str())
-			.json(&body)
-			.send()?
-			.json()?;
+			.json(body)
+			.send()?;
+		if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+			return Err(Error::Unauthorized);
+		}
+		let response: Response<T> = response.json()?;
 
 		if let Some(errors) = response.errors {
 			return Err(Error::GraphQL(errors));
 		}
-		let data = match response.data {
-			Some(data) => data,
-			None => return Err("Check in API returned no data".into()),
+		response.data.ok_or_else(|| "Check in API returned no data".into())
+	}
+
+	fn checkin_action(&self, check_in: bool, uuid: &str, tag: &str) -> Result<CheckInReturn, Error> {
+		self.checkin_outcome(check_in, uuid, tag)?.into_result()
+	}
+
+	/// When checking out, `AlreadyCheckedIn` means the user wasn't checked in to begin with
+	fn checkin_outcome(&self, check_in: bool, uuid: &str, tag: &str) -> Result<CheckInOutcome, Error> {
+		let result = match self.checkin_request(check_in, uuid, tag)? {
+			Some(result) => result,
+			None => return self.unknown_user_or_tag(tag),
 		};
+		if !self.is_eligible(&result.1) {
+			return Ok(CheckInOutcome::NotAccepted);
+		}
+		Ok(CheckInOutcome::from_result(result))
+	}
+
+	/// Sends the check-in mutation without looking at the user's eligibility
+	///
+	/// Returns `None` if the server didn't know the user or the tag
+	fn checkin_request(&self, check_in: bool, uuid: &str, tag: &str) -> Result<Option<CheckInReturn>, Error> {
+		let data = self.graphql::<CheckInTag>(check_in_tag::Variables {
+			id: uuid.to_string(),
+			tag: tag.to_string(),
+			checkin: check_in,
+		})?;
 		let check_in_data = match data.check_in {
 			Some(check_in_data) => check_in_data,
-			None => return Err("Invalid user ID on badge".into()),
+			None => return Ok(None),
 		};
 		let user = check_in_data.user.user_data;
-		if !user.accepted || !user.confirmed {
-			return Err("User not accepted and confirmed".into());
-		}
-
 		let tag_details = check_in_data.tags.into_iter()
 			.map(|item| item.tag_data)
 			.find(|item| item.tag.name == tag)
 			.unwrap(); // API ensures the tag we requested will be in the response so this won't panic
 
-		Ok((
-			tag_details.checkin_success,
-			user,
-			tag_details
-		))
+		Ok(Some((tag_details.checkin_success, user, tag_details)))
+	}
+
+	/// The API returns null for both an unknown user and an unknown tag so check which one it was
+	fn unknown_user_or_tag(&self, tag: &str) -> Result<CheckInOutcome, Error> {
+		let tag_exists = self.get_tags_names(false)?.iter().any(|name| name == tag);
+		if tag_exists {
+			Ok(CheckInOutcome::UnknownUser)
+		}
+		else {
+			Ok(CheckInOutcome::UnknownTag)
+		}
+	}
+
+	/// Whether the user may be checked in: accepted and, unless `allow_unconfirmed` is set, confirmed
+	fn is_eligible(&self, user: &check_in_tag::UserData) -> bool {
+		let eligibility = if self.allow_unconfirmed {
+			Eligibility::RequireAcceptedOnly
+		}
+		else {
+			Eligibility::RequireAcceptedAndConfirmed
+		};
+		eligibility.permits(user.accepted, user.confirmed)
 	}
 
 	/// Check a user into a tag
@@ -209,6 +573,63 @@ impl CheckinAPI {
 		self.checkin_action(true, uuid, tag)
 	}
 
+	/// Same as `check_in_outcome` but with the given eligibility rule instead of the `allow_unconfirmed` setting
+	///
+	/// The user is looked up and checked against the rule before the check-in is sent, so users who aren't eligible
+	/// are never checked in on the server. This costs an extra request.
+	pub fn check_in_outcome_with(&self, uuid: &str, tag: &str, eligibility: Eligibility) -> Result<CheckInOutcome, Error> {
+		let user = match self.get_user(uuid)? {
+			Some(user) => user,
+			None => return Ok(CheckInOutcome::UnknownUser),
+		};
+		if !eligibility.permits(user.accepted, user.confirmed) {
+			return Ok(CheckInOutcome::NotAccepted);
+		}
+		match self.checkin_request(true, uuid, tag)? {
+			Some(result) => Ok(CheckInOutcome::from_result(result)),
+			None => self.unknown_user_or_tag(tag),
+		}
+	}
+
+	/// Check a user into a tag even if they aren't eligible (e.g. an unconfirmed VIP let in by staff)
+	///
+	/// A reason is required. The check-in API has nowhere to store it, so it's handed back in the result for the
+	/// caller's own audit log. Unknown users and tags are still errors.
+	pub fn check_in_with_override(&self, uuid: &str, tag: &str, reason: &str) -> Result<OverrideCheckIn, Error> {
+		if reason.trim().is_empty() {
+			return Err("An override reason is required".into());
+		}
+		let result = match self.checkin_request(true, uuid, tag)? {
+			Some(result) => result,
+			None => self.unknown_user_or_tag(tag)?.into_result()?,
+		};
+		Ok(OverrideCheckIn {
+			overridden: !self.is_eligible(&result.1),
+			result,
+			reason: reason.to_owned(),
+		})
+	}
+
+	/// Check in a user from the text read off of their NFC badge or scanned from their QR code
+	///
+	/// Both encode the same badge URL so this gives NFC and QR check-in stations a single entry point
+	#[cfg(feature = "nfc")]
+	pub fn check_in_scanned(&self, scanned_text: &str, tag: &str) -> Result<CheckInReturn, Error> {
+		// Barcode scanners often send a trailing newline
+		let url = Url::parse(scanned_text.trim()).map_err(|_| "Invalid URL")?;
+		let uuid = crate::nfc::user_id_from_url(&url).ok_or("URL did not contain user ID")?;
+		self.check_in(&uuid, tag)
+	}
+
+	/// Check a user into a tag, reporting expected business outcomes as `Ok`
+	///
+	/// Unlike `check_in`, users who are already checked in, haven't been accepted, or don't exist are returned as a
+	/// `CheckInOutcome` so `Err` only means that something actually went wrong (network, auth, server). Users
+	/// reported as `NotAccepted` have still been checked in on the server; see `CheckInOutcome::NotAccepted`.
+	pub fn check_in_outcome(&self, uuid: &str, tag: &str) -> Result<CheckInOutcome, Error> {
+		self.checkin_outcome(true, uuid, tag)
+	}
+
 	/// Check a user out of tag
 	///
 	/// See documentation for `check_in` for more details
@@ -216,45 +637,391 @@ impl CheckinAPI {
 		self.checkin_action(false, uuid, tag)
 	}
 
+	/// Check a list of users into a tag
+	///
+	/// Returns one result per user in order. Stops at the first network error since the rest of the batch would most
+	/// likely fail too, so users past the end of the returned list were never sent. Pass the results to `resume_batch`
+	/// to finish the batch once the network is back.
+	pub fn check_in_batch(&self, uuids: &[&str], tag: &str) -> Vec<Result<CheckInReturn, Error>> {
+		let mut results = Vec::with_capacity(uuids.len());
+		self.resume_batch(&mut results, uuids, tag);
+		results
+	}
+
+	/// Finish a batch check-in started with `check_in_batch`
+	///
+	/// Only entries that failed with a network error and entries that were never sent are (re)tried. Check-ins that
+	/// already went through, or that the server rejected, are left alone so they aren't counted twice.
+	pub fn resume_batch(&self, results: &mut Vec<Result<CheckInReturn, Error>>, uuids: &[&str], tag: &str) {
+		for (result, uuid) in results.iter_mut().zip(uuids) {
+			if let Err(Error::Network(_)) = result {
+				*result = self.check_in(uuid, tag);
+				if let Err(Error::Network(_)) = result {
+					return;
+				}
+			}
+		}
+		for uuid in uuids.iter().skip(results.len()) {
+			let result = self.check_in(uuid, tag);
+			let network_error = matches!(result, Err(Error::Network(_)));
+			results.push(result);
+			if network_error {
+				return;
+			}
+		}
+	}
+
+	/// Check every member of a team into a tag
+	///
+	/// The API can't look up a team directly, so this pages through every user to find its members. Returns each
+	/// member's ID alongside their result, following the same rules as `check_in_batch`: members past the end of the
+	/// list were never sent because of a network error.
+	pub fn check_in_team(&self, team: &str, tag: &str) -> Result<Vec<TeamCheckIn>, Error> {
+		let mut members = Vec::new();
+		for user in self.iter_users() {
+			let user = user?;
+			if user.team.as_ref().is_some_and(|user_team| user_team.id == team) {
+				members.push(user.id);
+			}
+		}
+		if members.is_empty() {
+			return Err(Error::Message("No users found on team"));
+		}
+		let uuids: Vec<&str> = members.iter().map(String::as_str).collect();
+		let results = self.check_in_batch(&uuids, tag);
+		Ok(members.into_iter().zip(results).collect())
+	}
+
 	/// Get a list of tag names from the check-in instance
 	///
 	/// Can optionally be filtered to only include tags that are currently active (computed from `start` / `end` attributes in check-in database)
 	pub fn get_tags_names(&self, only_current: bool) -> Result<Vec<String>, Error> {
-		let body = TagsGet::build_query(tags_get::Variables {
+		let data = self.graphql::<TagsGet>(tags_get::Variables {
 			only_current
-		});
+		})?;
+		Ok(
+			data.tags.into_iter()
+				.map(|tag| tag.name)
+				.collect()
+		)
+	}
 
-		let response: Response<tags_get::ResponseData> = self.client.post(self.base_url.join("/graphql").unwrap())
-			.header(reqwest::header::COOKIE, self.auth_cookie.as_str())
-			.json(&body)
-			.send()?
-			.json()?;
+	/// Checks whether a tag with exactly this name exists on the check-in instance
+	///
+	/// Includes tags that aren't currently active. Call it at startup to catch a misconfigured tag name before the
+	/// first badge is scanned.
+	pub fn tag_exists(&self, tag: &str) -> Result<bool, Error> {
+		Ok(self.get_tags_names(false)?.iter().any(|name| name == tag))
+	}
 
-		if let Some(errors) = response.errors {
-			return Err(Error::GraphQL(errors));
-		}
-		if response.data.is_none() {
-			return Err("Check in API returned no data".into());
-		}
+	/// Get the number of users currently checked into a tag
+	pub fn get_tag_count(&self, tag: &str) -> Result<u64, Error> {
+		let data = self.graphql::<TagCounts>(tag_counts::Variables {
+			tags: Some(vec![tag.to_string()]),
+		})?;
+		// Tags without anyone checked in are left out of the response
 		Ok(
-			response.data.unwrap()
-				.tags.into_iter()
-				.map(|tag| tag.name)
+			data.tag_counts.into_iter()
+				.flatten()
+				.find(|item| item.name == tag)
+				.map(|item| item.count as u64)
+				.unwrap_or(0)
+		)
+	}
+
+	/// Check a user into a tag and get the new number of users checked into it, e.g. for a live display
+	///
+	/// The check-in mutation can't return counts so this makes a second request for the count right after
+	pub fn check_in_and_count(&self, uuid: &str, tag: &str) -> Result<(CheckInReturn, u64), Error> {
+		let result = self.check_in(uuid, tag)?;
+		let count = self.get_tag_count(tag)?;
+		Ok((result, count))
+	}
+
+	/// Look up a user's registration information without checking them in
+	///
+	/// Returns `None` if no user has this ID
+	pub fn get_user(&self, uuid: &str) -> Result<Option<UserDetails>, Error> {
+		let data = self.graphql::<UserGet>(user_get::Variables {
+			id: uuid.to_string(),
+		})?;
+		Ok(data.user.map(|user| user.user.user_data))
+	}
+
+	/// Get the names of the tags that a user is currently checked into
+	///
+	/// Useful for seeing everything an attendee still has open (e.g. before releasing them from bag check)
+	pub fn get_user_tags(&self, uuid: &str) -> Result<Vec<String>, Error> {
+		let data = self.graphql::<UserGet>(user_get::Variables {
+			id: uuid.to_string(),
+		})?;
+		let user = data.user.ok_or("Invalid user ID")?;
+		Ok(
+			user.tags.into_iter()
+				.map(|item| item.tag_data)
+				.filter(|item| item.checked_in)
+				.map(|item| item.tag.name)
 				.collect()
 		)
 	}
+
+	/// Look up the user ID from a badge to check that it belongs to a real user and show who they are
+	///
+	/// Lets staff cross-check the attendee's identity (e.g. against a photo ID) before granting access. An unknown
+	/// user ID is not an error but is returned with `known` set to false.
+	pub fn verify_badge(&self, uuid: &str) -> Result<BadgeVerification, Error> {
+		Ok(match self.get_user(uuid)? {
+			Some(user) => {
+				BadgeVerification {
+					known: true,
+					accepted: user.accepted,
+					confirmed: user.confirmed,
+					name: Some(user.name),
+				}
+			},
+			None => BadgeVerification {
+				known: false,
+				accepted: false,
+				confirmed: false,
+				name: None,
+			},
+		})
+	}
+
+	/// Iterate over every user on the check-in instance
+	///
+	/// Users are fetched lazily a page at a time, so stopping early skips the remaining requests. The iterator ends
+	/// after yielding an error.
+	pub fn iter_users(&self) -> impl Iterator<Item = Result<User, Error>> + '_ {
+		UserPages::new(self).map(|item| item.map(|item| item.user.user_data))
+	}
+
+	/// Iterate over every check in and check out on the check-in instance
+	///
+	/// Fetched lazily a page of users at a time like `iter_users`
+	pub fn iter_checkin_records(&self) -> impl Iterator<Item = Result<CheckinRecord, Error>> + '_ {
+		UserPages::new(self).flat_map(|item| {
+			let item = match item {
+				Ok(item) => item,
+				Err(err) => return vec![Err(err)],
+			};
+			let user = item.user.user_data;
+			let mut records = Vec::new();
+			for tag in item.tags {
+				let tag_name = tag.tag.name;
+				for detail in tag.details.into_iter().flatten() {
+					records.push(Ok(CheckinRecord {
+						uuid: user.id.clone(),
+						name: user.name.clone(),
+						tag: tag_name.clone(),
+						checked_in: detail.checked_in,
+						timestamp: detail.checked_in_date,
+						operator: detail.checked_in_by,
+					}));
+				}
+			}
+			records
+		})
+	}
+
+	/// Write every check-in record on the instance to `writer` as CSV
+	///
+	/// Rows are `uuid,name,tag,checked_in,timestamp,operator` with one row for each check in / check out event. Users are
+	/// fetched a page at a time and written out as they arrive so the full export is never held in memory.
+	pub fn export_checkins_csv<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+		writeln!(writer, "uuid,name,tag,checked_in,timestamp,operator")?;
+		for record in self.iter_checkin_records() {
+			let record = record?;
+			writeln!(
+				writer,
+				"{},{},{},{},{},{}",
+				csv_field(&record.uuid),
+				csv_field(&record.name),
+				csv_field(&record.tag),
+				record.checked_in,
+				csv_field(&record.timestamp),
+				csv_field(&record.operator),
+			)?;
+		}
+		writer.flush()?;
+		Ok(())
+	}
+}
+
+/// Pages through the `users` query, yielding one user (with their tags) at a time
+struct UserPages<'a> {
+	api: &'a CheckinAPI,
+	page: std::vec::IntoIter<users_get::UsersGetUsers>,
+	/// The ID of the last user seen, used to request the next page
+	pagination_token: Option<String>,
+	finished: bool,
+}
+
+impl<'a> UserPages<'a> {
+	fn new(api: &'a CheckinAPI) -> Self {
+		Self {
+			api,
+			page: Vec::new().into_iter(),
+			pagination_token: None,
+			finished: false,
+		}
+	}
+}
+
+impl Iterator for UserPages<'_> {
+	type Item = Result<users_get::UsersGetUsers, Error>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		const PAGE_SIZE: i64 = 500;
+
+		if let Some(item) = self.page.next() {
+			return Some(Ok(item));
+		}
+		if self.finished {
+			return None;
+		}
+		let data = match self.api.graphql::<UsersGet>(users_get::Variables {
+			pagination_token: self.pagination_token.take(),
+			number: PAGE_SIZE,
+		}) {
+			Ok(data) => data,
+			Err(err) => {
+				self.finished = true;
+				return Some(Err(err));
+			},
+		};
+		self.finished = (data.users.len() as i64) < PAGE_SIZE;
+		self.pagination_token = data.users.last().map(|item| item.user.pagination_token.clone());
+		self.page = data.users.into_iter();
+		self.page.next().map(Ok)
+	}
+}
+
+/// A `CheckinAPI` that logs in again on its own when its session expires
+///
+/// Meant for unattended kiosks. Credentials are kept in memory so that any call rejected with `Error::Unauthorized`
+/// can log in again and be retried once.
+pub struct AutoCheckinAPI {
+	api: RwLock<CheckinAPI>,
+	username: String,
+	password: String,
+}
+
+impl AutoCheckinAPI {
+	/// Log into the API and keep the credentials for logging in again later
+	pub fn login(username: &str, password: &str, url: &str) -> Result<Self, Error> {
+		Ok(AutoCheckinAPI::new(CheckinAPI::login(username, password, url)?, username, password))
+	}
+
+	/// Wraps an existing API instance (e.g. one created with `from_token` and configured with custom headers)
+	pub fn new(api: CheckinAPI, username: &str, password: &str) -> Self {
+		Self {
+			api: RwLock::new(api),
+			username: username.to_owned(),
+			password: password.to_owned(),
+		}
+	}
+
+	/// Runs an API operation, logging in again and retrying it once if the session has expired
+	///
+	/// `operation` can run twice so it shouldn't have side effects outside of the API call, e.g.
+	/// `api.call(|api| api.check_in(uuid, tag))`
+	pub fn call<R, F>(&self, operation: F) -> Result<R, Error>
+		where F: Fn(&CheckinAPI) -> Result<R, Error>,
+	{
+		let expired_token = {
+			let api = self.api.read().unwrap();
+			match operation(&api) {
+				Err(Error::Unauthorized) => api.auth_token().to_owned(),
+				result => return result,
+			}
+		};
+		{
+			let mut api = self.api.write().unwrap();
+			// Threads whose sessions expired together only log in once; the rest retry with the new token
+			if api.auth_token() == expired_token {
+				api.relogin(&self.username, &self.password)?;
+			}
+		}
+		operation(&self.api.read().unwrap())
+	}
+
+	/// The current auth token, which changes every time the session is renewed
+	pub fn auth_token(&self) -> String {
+		self.api.read().unwrap().auth_token().to_owned()
+	}
+}
+
+/// Quotes a CSV field if it contains any characters with special meaning
+fn csv_field(value: &str) -> Cow<'_, str> {
+	if value.contains([',', '"', '\n', '\r']) {
+		Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+	}
+	else {
+		Cow::Borrowed(value)
+	}
 }
 
 #[cfg(test)]
 mod checkin_api_tests {
-	use super::CheckinAPI;
+	use super::{ CheckinAPI, csv_field };
+	#[cfg(feature = "chrono")]
+	use super::{ CheckinRecord, parse_timestamp };
+
+	#[test]
+	fn csv_escaping() {
+		assert_eq!(csv_field("George P. Burdell"), "George P. Burdell");
+		assert_eq!(csv_field("Burdell, George"), "\"Burdell, George\"");
+		assert_eq!(csv_field("George \"Buzz\" Burdell"), "\"George \"\"Buzz\"\" Burdell\"");
+	}
+
+	#[test]
+	#[cfg(feature = "chrono")]
+	fn timestamps() {
+		let timestamp = parse_timestamp("2020-02-15T18:00:00.000Z").unwrap();
+		assert_eq!(timestamp.timestamp(), 1581789600);
+		assert!(parse_timestamp("Sat Feb 15 2020").is_err());
+
+		let record = CheckinRecord {
+			uuid: "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e".to_owned(),
+			name: "George P. Burdell".to_owned(),
+			tag: "lunch".to_owned(),
+			checked_in: true,
+			timestamp: "2020-02-15T18:00:00.000Z".to_owned(),
+			operator: "kiosk".to_owned(),
+		};
+		assert_eq!(record.timestamp_utc().unwrap(), timestamp);
+	}
+
+	#[test]
+	fn custom_headers() {
+		let instance = CheckinAPI::from_token("abc123".to_owned(), "https://checkin.hack.gt")
+			.with_user_agent("hackgt-kiosk/1.2")
+			.with_header("X-Device-Id", "kiosk-1")
+			.with_header("X-Device-Id", "kiosk-2");
+		assert_eq!(instance.headers[reqwest::header::USER_AGENT], "hackgt-kiosk/1.2");
+		assert_eq!(instance.headers["x-device-id"], "kiosk-2");
+		assert_eq!(instance.headers.len(), 2);
+	}
+
+	#[test]
+	#[cfg(feature = "nfc")]
+	fn check_in_scanned_invalid() {
+		let instance = CheckinAPI::from_token("abc123".to_owned(), "https://checkin.hack.gt");
+		assert!(matches!(instance.check_in_scanned("not a url", "123"), Err(super::Error::Message("Invalid URL"))));
+		assert!(matches!(
+			instance.check_in_scanned("https://live.hack.gt/\n", "123"),
+			Err(super::Error::Message("URL did not contain user ID"))
+		));
+	}
 
 	#[test]
 	fn login() {
 		let username = std::env::var("CHECKIN_USERNAME").unwrap();
 		let password = std::env::var("CHECKIN_PASSWORD").unwrap();
+		let url = std::env::var("CHECKIN_URL").unwrap();
 
-		let instance = CheckinAPI::login(&username, &password).unwrap();
+		let instance = CheckinAPI::login(&username, &password, &url).unwrap();
 		assert_eq!(instance.auth_token().len(), 64);
 
 		instance.check_in("7dd00021-89fd-49f1-9c17-bd0ba7dcf97e", "123").unwrap();
@@ -0,0 +1,34 @@
+use std::fmt;
+use crate::api;
+#[cfg(feature = "nfc")]
+use crate::nfc;
+
+/// Any error from this crate, for code that reads badges and talks to the check-in server in one go
+pub enum Error {
+	Api(api::Error),
+	#[cfg(feature = "nfc")]
+	Nfc(nfc::Error),
+}
+impl fmt::Debug for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Error::Api(err) => write!(f, "{:?}", err),
+			#[cfg(feature = "nfc")]
+			Error::Nfc(err) => write!(f, "{:?}", err),
+		}
+	}
+}
+impl From<api::Error> for Error {
+	fn from(err: api::Error) -> Error {
+		Error::Api(err)
+	}
+}
+#[cfg(feature = "nfc")]
+impl From<nfc::Error> for Error {
+	fn from(err: nfc::Error) -> Error {
+		Error::Nfc(err)
+	}
+}
+
+/// `Result` with the crate-wide `Error` as the default error type
+pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -1,3 +1,15 @@
+#![allow(clippy::upper_case_acronyms)]
+
+mod error;
+pub use error::{ Error, Result };
+
 #[cfg(feature = "nfc")]
 pub mod nfc;
 pub mod api;
+pub mod offline;
+#[cfg(feature = "nfc")]
+pub mod session;
+
+pub use api::Error as ApiError;
+#[cfg(feature = "nfc")]
+pub use nfc::Error as NfcError;
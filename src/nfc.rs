@@ -2,103 +2,483 @@ use pcsc::*;
 use std::thread::{ self, JoinHandle };
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::time::{ Duration, Instant };
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::{ self, Receiver, Sender };
 
 mod badge;
 mod ndef;
-pub use badge::NFCBadge;
+pub use badge::{ NFCBadge, CardTransport, TagModel, TagFamily, TagInfo, CapabilityContainer, ReadProgress, ReaderModel, BeepPattern, ApduExchange, TraceReplay, Error, user_id_from_url, user_id_from_url_raw, SDMData, sdm_data_from_url };
+pub use ndef::{ NDEF, NDEFBuilder, WellKnownType, RecordFlags };
 
-pub fn handle_cards<F, G>(card_handler: F, reader_handler: G) -> JoinHandle<()>
+/// `Result` with this module's `Error` as the default error type
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Settings for the reader loop started by `handle_cards_with`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReaderOptions {
+	/// The shortest time between two passes of the reader loop, and so between two card handler runs
+	///
+	/// The loop always blocks until PC/SC reports a change, so with a pcsclite that blocks properly this limits
+	/// nothing and only delays taps that come in less than an interval after the previous pass. It helps with pcsclite
+	/// builds that report changes in a busy loop, capping how often the loop runs. Good values for battery-powered
+	/// handhelds are around 100-250ms. By default (`None`) passes aren't throttled.
+	pub poll_interval: Option<Duration>,
+	/// The loop exits on its own once this has passed
+	pub deadline: Option<Instant>,
+}
+
+impl ReaderOptions {
+	/// How long the loop has to sleep before its next pass so that passes are at least `poll_interval` apart
+	fn throttle(&self, last_wake: Option<Instant>, now: Instant) -> Option<Duration> {
+		let mut next_wake = last_wake? + self.poll_interval?;
+		if let Some(deadline) = self.deadline {
+			next_wake = next_wake.min(deadline);
+		}
+		next_wake.checked_duration_since(now).filter(|delay| !delay.is_zero())
+	}
+}
+
+/// Messages that can be sent to a running reader loop through a `ReaderControl`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+	/// Drop the PC/SC context and establish a new one
+	///
+	/// Recovers from USB enumeration glitches (e.g. a replugged hub) that don't stop the smart card service. Every
+	/// reader is reported as removed and then added again.
+	Reestablish,
+	/// Exit the loop once the card handler that is currently running (if any) returns
+	Stop,
+}
+
+/// State shared between a reader loop and its `ReaderControl`s
+struct ControlState {
+	/// The loop's current context, used to wake it up while it waits for readers or cards
+	context: Mutex<Option<Context>>,
+	/// Set when a message is sent and cleared by the loop once it has read its messages
+	pending: AtomicBool,
+	/// Set by the loop from just before it checks `pending` until its wait for a status change returns
+	waiting: AtomicBool,
+}
+
+/// Sends `Control` messages to a running reader loop, obtained from `ReaderLoop::control`
+#[derive(Clone)]
+pub struct ReaderControl {
+	sender: Sender<Control>,
+	state: Arc<ControlState>,
+}
+
+impl ReaderControl {
+	/// Sends a message to the reader loop and wakes it up to handle it
+	///
+	/// Returns false if the reader loop has already exited
+	pub fn send(&self, control: Control) -> bool {
+		if self.sender.send(control).is_err() {
+			return false;
+		}
+		self.state.pending.store(true, Ordering::SeqCst);
+		// The loop either sees `pending` before it waits or is waiting (or about to) now. Cancelling a wait that hasn't
+		// started yet does nothing, so keep cancelling until the wait returns.
+		while self.state.waiting.load(Ordering::SeqCst) && self.state.pending.load(Ordering::SeqCst) {
+			if let Some(ctx) = &*self.state.context.lock().unwrap() {
+				let _ = ctx.cancel();
+			}
+			thread::sleep(Duration::from_millis(1));
+		}
+		true
+	}
+
+	/// Shorthand for `send(Control::Reestablish)`
+	pub fn reestablish(&self) -> bool {
+		self.send(Control::Reestablish)
+	}
+}
+
+/// A handle to the reader loop thread started by `handle_cards` and friends
+pub struct ReaderLoop {
+	handle: JoinHandle<()>,
+	running: Arc<AtomicBool>,
+	control: ReaderControl,
+}
+
+impl ReaderLoop {
+	/// Whether the loop is still running
+	///
+	/// Becomes false once the loop exits for any reason: it was stopped, its deadline passed, or it panicked. A
+	/// supervisor can poll this to notice a dead loop and start a new one.
+	pub fn is_running(&self) -> bool {
+		self.running.load(Ordering::SeqCst)
+	}
+
+	/// Asks the loop to exit. Use `join` to wait for it to finish.
+	pub fn stop(&self) {
+		self.control.send(Control::Stop);
+	}
+
+	/// Waits for the loop to exit, returning the panic payload if it panicked
+	pub fn join(self) -> thread::Result<()> {
+		self.handle.join()
+	}
+
+	/// Sends other `Control` messages to the loop
+	pub fn control(&self) -> &ReaderControl {
+		&self.control
+	}
+}
+
+/// Marks the reader loop as stopped when dropped, which also happens if the loop panics
+struct RunningGuard(Arc<AtomicBool>);
+impl Drop for RunningGuard {
+	fn drop(&mut self) {
+		self.0.store(false, Ordering::SeqCst);
+	}
+}
+
+type CardHandler = dyn Fn(&Card, &CStr, usize) + Send;
+
+/// Card handlers picked by reader, for setups where each physical reader runs different logic (e.g. one lane checks
+/// people into lunch while another is an info kiosk)
+///
+/// Handlers registered for a reader name are tried first, then handlers registered for a reader index, then the
+/// default handler. Cards tapped on readers without any matching handler are ignored. Pass to
+/// `handle_cards_per_reader` to start a reader loop.
+#[derive(Default)]
+pub struct ReaderHandlers {
+	by_name: HashMap<String, Box<CardHandler>>,
+	by_index: HashMap<usize, Box<CardHandler>>,
+	default: Option<Box<CardHandler>>,
+}
+
+impl ReaderHandlers {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Handles cards tapped on the reader with this exact name (e.g. `ACS ACR122U PICC Interface 00 00`)
+	pub fn with_reader<F>(mut self, name: &str, handler: F) -> Self
+		where F: Fn(&Card, &CStr, usize) + Send + 'static
+	{
+		self.by_name.insert(name.to_owned(), Box::new(handler));
+		self
+	}
+
+	/// Handles cards tapped on the reader with this index, as passed to `handle_cards` card handlers
+	///
+	/// Indexes follow the order readers were connected in so names are more reliable when readers come and go
+	pub fn with_index<F>(mut self, index: usize, handler: F) -> Self
+		where F: Fn(&Card, &CStr, usize) + Send + 'static
+	{
+		self.by_index.insert(index, Box::new(handler));
+		self
+	}
+
+	/// Handles cards tapped on readers that no other handler is registered for
+	pub fn with_default<F>(mut self, handler: F) -> Self
+		where F: Fn(&Card, &CStr, usize) + Send + 'static
+	{
+		self.default = Some(Box::new(handler));
+		self
+	}
+
+	/// Runs the handler registered for this reader, if there is one. Returns false if the card was ignored.
+	pub fn handle(&self, card: &Card, reader_name: &CStr, reader_index: usize) -> bool {
+		let handler = self.by_name.get(reader_name.to_string_lossy().as_ref())
+			.or_else(|| self.by_index.get(&reader_index))
+			.or(self.default.as_ref());
+		match handler {
+			Some(handler) => {
+				handler(card, reader_name, reader_index);
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+/// Same as `handle_cards` but each reader's cards go to the handler registered for it in `handlers`
+pub fn handle_cards_per_reader<G>(handlers: ReaderHandlers, reader_handler: G) -> ReaderLoop
+	where G: Fn(&CStr, bool),
+		  G: Send + 'static,
+{
+	handle_cards(move |card, reader_name, reader_index| {
+		handlers.handle(card, reader_name, reader_index);
+	}, reader_handler)
+}
+
+/// Starts a reader loop that calls `card_handler` for every tapped card and `reader_handler` whenever a reader is
+/// connected (`true`) or disconnected (`false`)
+///
+/// Each card handler runs inside an exclusive PC/SC transaction, so multi-command sequences like chunked reads,
+/// `NFCBadge::lock_pages`, and `NFCBadge::write_provision_version` can't be interleaved with another process using
+/// the card. Other processes have to wait for the handler to return before they can talk to the card.
+pub fn handle_cards<F, G>(card_handler: F, reader_handler: G) -> ReaderLoop
 	where F: Fn(&Card, &CStr, usize),
 		  F: Send + 'static,
 		  G: Fn(&CStr, bool),
 		  G: Send + 'static,
 {
-	thread::spawn(move || {
-		let mut ctx = Context::establish(Scope::User).expect("Failed to establish context");
-
-		let mut readers_buf = [0; 2048];
-		let mut reader_states = vec![
-			// Listen for reader insertions/removals, if supported
-			ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
-		];
-		// Keeps track of which readers have an active card
-		let mut readers = HashMap::new();
-		loop {
-			// Remove dead readers
-			fn is_invalid(rs: &ReaderState) -> bool {
-				rs.event_state().intersects(State::UNKNOWN | State::IGNORE)
-			}
-			reader_states.retain(|rs| {
-				let should_keep = !is_invalid(rs);
-				if !should_keep {
-					// Notify about removal
-					reader_handler(rs.name(), false);
-				}
-				should_keep
-			});
-
-			// Add new readers
-			let names = match ctx.list_readers(&mut readers_buf) {
-				Ok(names) => names,
-				Err(pcsc::Error::ServiceStopped) | Err(pcsc::Error::NoService) => {
-					// Windows will kill the SmartCard service when the last reader is disconnected
-					// Restart it and wait (sleep) for a new reader connection if that occurs
-					ctx = Context::establish(Scope::User).expect("Failed to establish context");
-					continue;
-				}
-				Err(err) => { panic!("Failed to list readers: {:?}", err) }
-			};
-
-			for name in names {
-				// Ignore the pseudo reader created by Windows Hello
-				if !reader_states.iter().any(|rs| rs.name() == name) && !name.to_str().unwrap().contains("Windows Hello") {
-					reader_handler(name, true);
-					reader_states.push(ReaderState::new(name, State::UNAWARE));
+	handle_cards_with(ReaderOptions::default(), card_handler, reader_handler)
+}
+
+/// Same as `handle_cards` but the reader loop exits on its own once `deadline` has passed
+///
+/// Useful for sessions that run for a fixed window of time. The returned handle can simply be joined.
+pub fn handle_cards_until<F, G>(deadline: Instant, card_handler: F, reader_handler: G) -> ReaderLoop
+	where F: Fn(&Card, &CStr, usize),
+		  F: Send + 'static,
+		  G: Fn(&CStr, bool),
+		  G: Send + 'static,
+{
+	handle_cards_with(ReaderOptions { deadline: Some(deadline), ..Default::default() }, card_handler, reader_handler)
+}
+
+/// Same as `handle_cards` with extra settings for the reader loop
+pub fn handle_cards_with<F, G>(options: ReaderOptions, card_handler: F, reader_handler: G) -> ReaderLoop
+	where F: Fn(&Card, &CStr, usize),
+		  F: Send + 'static,
+		  G: Fn(&CStr, bool),
+		  G: Send + 'static,
+{
+	let (sender, receiver) = mpsc::channel();
+	let state = Arc::new(ControlState {
+		context: Mutex::new(None),
+		pending: AtomicBool::new(false),
+		waiting: AtomicBool::new(false),
+	});
+	let control = ReaderControl { sender, state: Arc::clone(&state) };
+	let running = Arc::new(AtomicBool::new(true));
+	let guard = RunningGuard(Arc::clone(&running));
+	let handle = thread::spawn(move || {
+		let _guard = guard;
+		card_loop(options, receiver, state, card_handler, reader_handler)
+	});
+	ReaderLoop { handle, running, control }
+}
+
+/// Waits for a badge to be tapped on any reader and returns the user ID on it
+///
+/// Meant for one-off "scan your badge now" prompts. A badge that is already lying on a reader counts as tapped. Fails
+/// with `Error::Message("Scan timed out")` if no badge shows up before `timeout` has passed.
+pub fn read_badge_once(timeout: Duration) -> Result<String> {
+	let deadline = Instant::now() + timeout;
+	let ctx = Context::establish(Scope::User)?;
+
+	let mut readers_buf = [0; 2048];
+	let mut reader_states = vec![
+		// Pick up readers plugged in while waiting
+		ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
+	];
+	loop {
+		reader_states.retain(|rs| !rs.event_state().intersects(State::UNKNOWN | State::IGNORE));
+		match ctx.list_readers(&mut readers_buf) {
+			Ok(names) => {
+				for name in names {
+					if !reader_states.iter().any(|rs| rs.name() == name) && !name.to_string_lossy().contains("Windows Hello") {
+						reader_states.push(ReaderState::new(name, State::UNAWARE));
+					}
 				}
+			},
+			Err(pcsc::Error::NoReadersAvailable) => {},
+			Err(err) => return Err(err.into()),
+		}
+		for rs in &mut reader_states {
+			rs.sync_current_state();
+		}
+
+		let now = Instant::now();
+		if now >= deadline {
+			return Err(Error::Message("Scan timed out"));
+		}
+		match ctx.get_status_change(deadline - now, &mut reader_states) {
+			Ok(()) => {},
+			Err(pcsc::Error::Timeout) => return Err(Error::Message("Scan timed out")),
+			Err(err) => return Err(err.into()),
+		}
+
+		for rs in &reader_states {
+			if rs.name() == PNP_NOTIFICATION() || !rs.event_state().intersects(State::PRESENT) {
+				continue;
+			}
+			match ctx.connect(rs.name(), ShareMode::Shared, Protocols::ANY) {
+				Ok(mut card) => return NFCBadge::transaction(&mut card, |badge| badge.get_user_id()),
+				// Lifted off again before we could connect
+				Err(pcsc::Error::NoSmartcard) | Err(pcsc::Error::RemovedCard) => continue,
+				Err(err) => return Err(err.into()),
 			}
+		}
+	}
+}
 
-			// Update the view of the state to wait on
-			for rs in &mut reader_states {
-				rs.sync_current_state();
+fn establish_context(shared: &Mutex<Option<Context>>) -> Context {
+	let ctx = Context::establish(Scope::User).expect("Failed to establish context");
+	*shared.lock().unwrap() = Some(ctx.clone());
+	ctx
+}
+
+fn card_loop<F, G>(options: ReaderOptions, control: Receiver<Control>, state: Arc<ControlState>, card_handler: F, reader_handler: G)
+	where F: Fn(&Card, &CStr, usize),
+		  G: Fn(&CStr, bool),
+{
+	let shared_ctx = &state.context;
+	let mut ctx = establish_context(shared_ctx);
+
+	let mut readers_buf = [0; 2048];
+	let mut reader_states = vec![
+		// Listen for reader insertions/removals, if supported
+		ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
+	];
+	// Keeps track of which readers have an active card
+	let mut readers = HashMap::new();
+	let mut last_wake: Option<Instant> = None;
+	loop {
+		if let Some(delay) = options.throttle(last_wake, Instant::now()) {
+			thread::sleep(delay);
+		}
+		last_wake = Some(Instant::now());
+
+		state.pending.store(false, Ordering::SeqCst);
+		while let Ok(message) = control.try_recv() {
+			match message {
+				Control::Reestablish => {
+					// Forget every reader so that they are picked up again with the new context
+					reader_states.retain(|rs| {
+						let is_pnp = rs.name() == PNP_NOTIFICATION();
+						if !is_pnp {
+							reader_handler(rs.name(), false);
+						}
+						is_pnp
+					});
+					*shared_ctx.lock().unwrap() = None;
+					drop(ctx);
+					ctx = establish_context(shared_ctx);
+				},
+				Control::Stop => return,
 			}
+		}
 
-			// Wait until the state changes
-			match ctx.get_status_change(None, &mut reader_states) {
-				Ok(()) => {},
-				Err(pcsc::Error::ServiceStopped) | Err(pcsc::Error::NoService) => {
-					// Windows will kill the SmartCard service when the last reader is disconnected
-					// Restart it and wait (sleep) for a new reader connection if that occurs
-					ctx = Context::establish(Scope::User).expect("Failed to establish context");
-					continue;
-				}
-				Err(err) => { panic!("Failed to get status change: {:?}", err) }
-			};
-
-			for (reader_index, rs) in reader_states.iter().enumerate() {
-				if rs.name() == PNP_NOTIFICATION() { continue; }
-
-				let name = rs.name().to_owned();
-				// Debounce repeated events
-				if rs.event_state().intersects(State::PRESENT) {
-					if !readers.get(&name).unwrap_or(&false) {
-						// Card is tapped
-						// Connect to the card.
-						match ctx.connect(rs.name(), ShareMode::Shared, Protocols::ANY) {
-							Ok(card) => card_handler(&card, rs.name(), reader_index),
-							Err(Error::NoSmartcard) => {
-								eprintln!("A smartcard is not present in the reader");
-							}
-							Err(err) => {
-								eprintln!("Failed to connect to card: {}", err);
-							}
-						};
-					}
-					readers.insert(name, true);
+		let timeout = match options.deadline {
+			Some(deadline) => {
+				let now = Instant::now();
+				if now >= deadline {
+					return;
 				}
-				else if rs.event_state().intersects(State::EMPTY) {
-					readers.insert(name, false);
+				Some(deadline - now)
+			},
+			None => None,
+		};
+
+		// Remove dead readers
+		fn is_invalid(rs: &ReaderState) -> bool {
+			rs.event_state().intersects(State::UNKNOWN | State::IGNORE)
+		}
+		reader_states.retain(|rs| {
+			let should_keep = !is_invalid(rs);
+			if !should_keep {
+				// Notify about removal
+				reader_handler(rs.name(), false);
+			}
+			should_keep
+		});
+
+		// Add new readers
+		let names = match ctx.list_readers(&mut readers_buf) {
+			Ok(names) => names,
+			Err(pcsc::Error::ServiceStopped) | Err(pcsc::Error::NoService) => {
+				// Windows will kill the SmartCard service when the last reader is disconnected
+				// Restart it and wait (sleep) for a new reader connection if that occurs
+				ctx = establish_context(shared_ctx);
+				continue;
+			}
+			Err(err) => { panic!("Failed to list readers: {:?}", err) }
+		};
+
+		for name in names {
+			// Ignore the pseudo reader created by Windows Hello
+			if !reader_states.iter().any(|rs| rs.name() == name) && !name.to_string_lossy().contains("Windows Hello") {
+				reader_handler(name, true);
+				reader_states.push(ReaderState::new(name, State::UNAWARE));
+			}
+		}
+
+		// Update the view of the state to wait on
+		for rs in &mut reader_states {
+			rs.sync_current_state();
+		}
+
+		// A message sent after the check at the top of the loop would otherwise wait for the next PC/SC event
+		state.waiting.store(true, Ordering::SeqCst);
+		if state.pending.load(Ordering::SeqCst) {
+			state.waiting.store(false, Ordering::SeqCst);
+			continue;
+		}
+		// Wait until the state changes
+		let status = ctx.get_status_change(timeout, &mut reader_states);
+		state.waiting.store(false, Ordering::SeqCst);
+		match status {
+			Ok(()) => {},
+			// Deadline reached, checked at the top of the loop
+			Err(pcsc::Error::Timeout) => continue,
+			// Woken up by a `ReaderControl`, messages are handled at the top of the loop
+			Err(pcsc::Error::Cancelled) => continue,
+			Err(pcsc::Error::ServiceStopped) | Err(pcsc::Error::NoService) => {
+				// Windows will kill the SmartCard service when the last reader is disconnected
+				// Restart it and wait (sleep) for a new reader connection if that occurs
+				ctx = establish_context(shared_ctx);
+				continue;
+			}
+			Err(err) => { panic!("Failed to get status change: {:?}", err) }
+		};
+
+		for (reader_index, rs) in reader_states.iter().enumerate() {
+			if rs.name() == PNP_NOTIFICATION() { continue; }
+
+			let name = rs.name().to_owned();
+			// Debounce repeated events
+			if rs.event_state().intersects(State::PRESENT) {
+				if !readers.get(&name).unwrap_or(&false) {
+					// Card is tapped
+					// Connect to the card.
+					match ctx.connect(rs.name(), ShareMode::Shared, Protocols::ANY) {
+						Ok(mut card) => match card.transaction2() {
+							Ok(transaction) => card_handler(&transaction, rs.name(), reader_index),
+							// Lifted off before the transaction could start, which the handler will report
+							Err((card, _)) => card_handler(card, rs.name(), reader_index),
+						},
+						Err(pcsc::Error::NoSmartcard) => {
+							eprintln!("A smartcard is not present in the reader");
+						}
+						Err(err) => {
+							eprintln!("Failed to connect to card: {}", err);
+						}
+					};
 				}
+				readers.insert(name, true);
+			}
+			else if rs.event_state().intersects(State::EMPTY) {
+				readers.insert(name, false);
 			}
 		}
-	})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::ReaderOptions;
+	use std::time::{ Duration, Instant };
+
+	#[test]
+	fn poll_interval_throttles_passes() {
+		let now = Instant::now();
+		let options = ReaderOptions { poll_interval: Some(Duration::from_millis(100)), deadline: None };
+		// A pass that comes soon after the previous one waits out the rest of the interval
+		assert_eq!(options.throttle(Some(now - Duration::from_millis(30)), now), Some(Duration::from_millis(70)));
+		assert_eq!(options.throttle(Some(now - Duration::from_millis(150)), now), None);
+		assert_eq!(options.throttle(None, now), None);
+
+		let options = ReaderOptions { deadline: Some(now + Duration::from_millis(20)), ..options };
+		assert_eq!(options.throttle(Some(now), now), Some(Duration::from_millis(20)));
+
+		assert_eq!(ReaderOptions::default().throttle(Some(now), now), None);
+	}
 }
@@ -1,10 +1,16 @@
 use std::fmt;
+use std::io;
 use std::borrow::Cow;
+use std::cell::{ Cell, RefCell };
+use std::ffi::{ CStr, CString };
+use std::thread;
+use std::time::{ Duration, Instant };
 use url::Url;
-use super::ndef::NDEF;
+use super::ndef::{ NDEF, WellKnownType };
 
 #[derive(Debug)]
 pub struct CardResponse {
+	#[allow(dead_code)]
 	pub status: [u8; 2],
 	pub data: Vec<u8>,
 }
@@ -14,13 +20,37 @@ pub enum Error {
 	PCSC(pcsc::Error),
 	Response([u8; 2]),
 	Message(&'static str),
+	IO(io::Error),
 }
+impl Error {
+	/// Creates an error with a custom message, for wrapping badge operations in other abstractions
+	pub fn message(message: &'static str) -> Self {
+		Error::Message(message)
+	}
+
+	/// Creates an error from the two status bytes of a card response
+	pub fn from_status(status: [u8; 2]) -> Self {
+		Error::Response(status)
+	}
+
+	/// Whether the tag was lifted off the reader partway through an operation
+	///
+	/// This is something the attendee can fix, so it's worth asking them to tap again instead of showing an error
+	pub fn is_tag_removed(&self) -> bool {
+		matches!(self, Error::Message(message) if *message == TAG_REMOVED_MESSAGE)
+	}
+}
+
+const TAG_REMOVED_MESSAGE: &str = "Tag removed during read \u{2014} please hold the badge still";
+const UNSUPPORTED_TAG_MODEL_MESSAGE: &str = "Unsupported tag model";
+const TAG_REJECTED_WRITE_MESSAGE: &str = "Tag rejected write";
 impl fmt::Debug for Error {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
+		match self {
 			Error::PCSC(pcsc_error) => write!(f, "{:?}", pcsc_error),
 			Error::Response(bytes) => write!(f, "{:x?}", bytes),
 			Error::Message(s) => write!(f, "{}", s),
+			Error::IO(err) => write!(f, "{:?}", err),
 		}
 	}
 }
@@ -29,6 +59,11 @@ impl From<pcsc::Error> for Error {
 		Error::PCSC(err)
 	}
 }
+impl From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::IO(err)
+	}
+}
 impl From<[u8; 2]> for Error {
 	fn from(err: [u8; 2]) -> Error {
 		Error::Response(err)
@@ -40,18 +75,725 @@ impl From<&'static str> for Error {
 	}
 }
 
-pub struct NFCBadge<'a> {
-	card: &'a pcsc::Card,
+/// Anything that can exchange APDUs with a tag
+///
+/// Implemented for `pcsc::Card` so `NFCBadge::new(&card)` works as before, but it also lets the badge logic run
+/// against a recorded or simulated tag
+pub trait CardTransport {
+	fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error>;
+
+	/// Sends a reader-specific escape command (used for reader features that aren't reachable through APDUs)
+	fn escape(&self, _command: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+		Err(pcsc::Error::UnsupportedFeature)
+	}
+
+	/// The name of the reader the tag is connected through, if known
+	fn reader_name(&self) -> Option<CString> {
+		None
+	}
+
+	/// The protocol (T=0 or T=1) negotiated when connecting to the tag, if known
+	fn protocol(&self) -> Option<pcsc::Protocol> {
+		None
+	}
+
+	/// The ATR the reader built for the tag, if known
+	fn atr(&self) -> Option<Vec<u8>> {
+		None
+	}
 }
+impl CardTransport for pcsc::Card {
+	fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+		let mut rapdu_buf = [0u8; pcsc::MAX_BUFFER_SIZE];
+		Ok(pcsc::Card::transmit(self, apdu, &mut rapdu_buf)?.to_vec())
+	}
+
+	fn escape(&self, command: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+		// IOCTL_CCID_ESCAPE
+		const ESCAPE_CODE: u32 = 3500;
+		let mut response_buf = [0u8; pcsc::MAX_BUFFER_SIZE];
+		Ok(self.control(pcsc::ctl_code(ESCAPE_CODE.into()), command, &mut response_buf)?.to_vec())
+	}
+
+	fn reader_name(&self) -> Option<CString> {
+		self.status2_owned().ok()?.reader_names().first().cloned()
+	}
 
-impl NFCBadge<'_> {
-	pub fn new(card: &pcsc::Card) -> NFCBadge {
+	fn protocol(&self) -> Option<pcsc::Protocol> {
+		self.status2_owned().ok()?.protocol2()
+	}
+
+	fn atr(&self) -> Option<Vec<u8>> {
+		Some(self.status2_owned().ok()?.atr().to_vec())
+	}
+}
+
+/// One APDU sent to the card and what came back, as recorded by `NFCBadge::set_tracing`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApduExchange {
+	pub request: Vec<u8>,
+	/// The response without its status bytes
+	pub response: Vec<u8>,
+	pub status: [u8; 2],
+}
+
+/// Plays back a recorded trace as if it were the tag that the trace came from
+///
+/// Each APDU has to match the next request in the trace. A mismatch or running out of exchanges is reported as a
+/// `pcsc::Error::InvalidValue` so replaying a trace with changed reading logic fails instead of giving made up data.
+pub struct TraceReplay {
+	exchanges: Vec<ApduExchange>,
+	position: Cell<usize>,
+}
+impl TraceReplay {
+	pub fn new(exchanges: Vec<ApduExchange>) -> Self {
+		Self { exchanges, position: Cell::new(0) }
+	}
+
+	/// Whether every exchange in the trace has been played back
+	pub fn finished(&self) -> bool {
+		self.position.get() == self.exchanges.len()
+	}
+}
+impl CardTransport for TraceReplay {
+	fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+		let exchange = self.exchanges.get(self.position.get()).ok_or(pcsc::Error::InvalidValue)?;
+		if exchange.request != apdu {
+			return Err(pcsc::Error::InvalidValue);
+		}
+		self.position.set(self.position.get() + 1);
+		let mut response = exchange.response.clone();
+		response.extend_from_slice(&exchange.status);
+		Ok(response)
+	}
+}
+
+/// A buzzer sequence for `NFCBadge::beep`
+///
+/// Durations are in units of 100 ms, which is what the readers use internally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeepPattern {
+	/// How long each beep lasts
+	pub on: u8,
+	/// The pause after each beep
+	pub off: u8,
+	pub repetitions: u8,
+}
+impl BeepPattern {
+	/// A single short beep
+	pub const SUCCESS: BeepPattern = BeepPattern { on: 1, off: 0, repetitions: 1 };
+	/// Two long beeps
+	pub const FAILURE: BeepPattern = BeepPattern { on: 4, off: 2, repetitions: 2 };
+}
+
+/// The USB NFC readers that are supported for LED and buzzer control
+///
+/// Both can read badges in the same way, but their LED and buzzer commands are completely different
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReaderModel {
+	ACR122U,
+	ACR1252U,
+}
+impl ReaderModel {
+	/// Detects the reader model from the name that PC/SC gives the reader
+	///
+	/// Unrecognized readers are assumed to be ACR122U compatible
+	pub fn from_name(name: &CStr) -> Self {
+		if name.to_string_lossy().contains("ACR1252") {
+			ReaderModel::ACR1252U
+		}
+		else {
+			ReaderModel::ACR122U
+		}
+	}
+}
+
+/// The NXP NTAG21x tags that HackGT badges are printed on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagModel {
+	NTAG213,
+	NTAG215,
+	NTAG216,
+}
+impl TagModel {
+	/// Identifies the tag from the storage size byte of a GET_VERSION response
+	fn from_version(version: &[u8]) -> Option<Self> {
+		// Vendor 0x04 (NXP), product type 0x04 (NTAG)
+		if version.len() < 8 || version[1] != 0x04 || version[2] != 0x04 {
+			return None;
+		}
+		match version[6] {
+			0x0F => Some(TagModel::NTAG213),
+			0x11 => Some(TagModel::NTAG215),
+			0x13 => Some(TagModel::NTAG216),
+			_ => None,
+		}
+	}
+
+	/// The first and last page of user memory
+	///
+	/// Pages after the last user page hold the dynamic lock bytes and configuration pages which aren't part of the
+	/// NDEF data area and must never be fed into the parser
+	pub fn user_pages(self) -> (u8, u8) {
+		// 0x00 through 0x03 contain tag-related info. User data starts at 0x04
+		match self {
+			TagModel::NTAG213 => (0x04, 0x27),
+			TagModel::NTAG215 => (0x04, 0x81),
+			TagModel::NTAG216 => (0x04, 0xE1),
+		}
+	}
+}
+
+/// Broad families of ISO 14443-A tags, which each need a different way of reading them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagFamily {
+	/// Type 2 tags like the NTAG21x and MIFARE Ultralight, read with FAST_READ
+	Ultralight,
+	/// MIFARE Classic, which needs sector authentication
+	MifareClassic,
+	/// MIFARE DESFire, a Type 4 tag
+	DESFire,
+	/// Any other ISO-DEP (ISO 14443-4) target, e.g. a phone in card emulation mode, read as a Type 4 tag
+	ISODep,
+	/// An ISO 15693 vicinity tag, read as a Type 5 tag
+	ISO15693,
+	Unknown,
+}
+impl TagFamily {
+	/// Classifies a tag from the SAK and ATQA it sent during anticollision
+	pub fn from_sak_atqa(sak: u8, atqa: [u8; 2]) -> Self {
+		match sak {
+			0x00 => TagFamily::Ultralight,
+			0x08 | 0x09 | 0x18 | 0x19 | 0x88 => TagFamily::MifareClassic,
+			sak if sak & 0x20 != 0 && atqa == [0x03, 0x44] => TagFamily::DESFire,
+			sak if sak & 0x20 != 0 => TagFamily::ISODep,
+			_ => TagFamily::Unknown,
+		}
+	}
+
+	/// Classifies a tag from the ATR the PC/SC reader built for it
+	///
+	/// Memory cards get the card name from the PC/SC part 3 supplemental document after the registered application
+	/// provider identifier while ISO-DEP targets get their historical bytes instead
+	pub fn from_atr(atr: &[u8]) -> Self {
+		const PCSC_RID: [u8; 5] = [0xA0, 0x00, 0x00, 0x03, 0x06];
+		let rid_index = match atr.windows(PCSC_RID.len()).position(|window| window == PCSC_RID) {
+			Some(index) => index,
+			None => return TagFamily::ISODep,
+		};
+		// ISO 15693 part 3 or part 4 in the standard byte
+		if let Some(0x0B) | Some(0x0C) = atr.get(rid_index + 5) {
+			return TagFamily::ISO15693;
+		}
+		// RID, then the standard byte, then the two byte card name
+		match atr.get(rid_index + 6..rid_index + 8) {
+			Some([0x00, 0x03]) => TagFamily::Ultralight,
+			Some([0x00, 0x01]) | Some([0x00, 0x02]) | Some([0x00, 0x26]) => TagFamily::MifareClassic,
+			_ => TagFamily::Unknown,
+		}
+	}
+}
+
+/// Identifying information about the tag on the reader
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagInfo {
+	pub uid: Vec<u8>,
+	/// Only available through readers with a PN532 (e.g. the ACR122U)
+	pub atqa: Option<[u8; 2]>,
+	/// Only available through readers with a PN532 (e.g. the ACR122U)
+	pub sak: Option<u8>,
+	pub family: TagFamily,
+}
+
+/// The capability container (page 0x03) of an NDEF formatted Type 2 tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapabilityContainer {
+	/// The NFC Forum Type 2 Tag mapping version, from the high nibble of byte 1
+	pub major_version: u8,
+	/// From the low nibble of byte 1
+	pub minor_version: u8,
+	/// The size of the NDEF data area in bytes
+	pub data_area_size: usize,
+	/// Whether the write access nibble denies writes
+	pub read_only: bool,
+}
+
+impl CapabilityContainer {
+	/// Whether phones and this crate can be expected to read the tag: only major version 1 of the Type 2 Tag mapping
+	/// exists, while newer minor versions are meant to stay compatible
+	pub fn is_supported_version(&self) -> bool {
+		self.major_version == 1
+	}
+}
+
+/// Reported to the handler set with `NFCBadge::set_progress_handler` after each attempt at a tag command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadProgress {
+	/// Starts at 1 and goes up each time the same command is retried
+	pub attempt: u32,
+	/// The PN532 status byte: 0x00 for success, otherwise the error (e.g. 0x01 for a timeout)
+	pub status: u8,
+}
+
+pub struct NFCBadge<'a, T: CardTransport + ?Sized = pcsc::Card> {
+	card: &'a T,
+	/// The answer to GET_VERSION once the tag has given one, with `None` for tags that aren't a known NTAG21x
+	tag_model: Cell<Option<Option<TagModel>>>,
+	progress_handler: Option<Box<dyn Fn(ReadProgress) + 'a>>,
+	retries: u32,
+	retry_deadline: Option<Duration>,
+	reader_model: ReaderModel,
+	trace: Option<RefCell<Vec<ApduExchange>>>,
+	max_response_size: usize,
+	accept_text_uuids: bool,
+}
+
+impl<'a, T: CardTransport + ?Sized> NFCBadge<'a, T> {
+	pub fn new(card: &'a T) -> NFCBadge<'a, T> {
+		let reader_model = card.reader_name()
+			.map(|name| ReaderModel::from_name(&name))
+			.unwrap_or(ReaderModel::ACR122U);
 		NFCBadge {
 			card,
+			tag_model: Cell::new(None),
+			progress_handler: None,
+			retries: 2,
+			retry_deadline: None,
+			reader_model,
+			trace: None,
+			max_response_size: pcsc::MAX_BUFFER_SIZE - 2,
+			accept_text_uuids: false,
 		}
 	}
 
+	/// The reader model that LED and buzzer commands are sent for
+	pub fn reader_model(&self) -> ReaderModel {
+		self.reader_model
+	}
+
+	/// The protocol the card connected with
+	///
+	/// Card handlers connect with `Protocols::ANY` so this tells you which one the reader actually picked, which helps
+	/// when a tag behaves differently on readers that default to different protocols
+	pub fn protocol(&self) -> Option<pcsc::Protocol> {
+		self.card.protocol()
+	}
+
+	/// Overrides the reader model detected from the reader's name
+	pub fn set_reader_model(&mut self, reader_model: ReaderModel) {
+		self.reader_model = reader_model;
+	}
+
+	/// Sets how many times a tag command is retried when the PN532 reports a transient error (defaults to 2)
+	///
+	/// Tags at the edge of the reader's field often time out on the first attempt but succeed once they've settled
+	pub fn set_retries(&mut self, retries: u32) {
+		self.retries = retries;
+	}
+
+	/// Stops retrying a tag command once this much time has passed since its first attempt, giving up with
+	/// `Error::Message("Card read timed out")` so the attendee can be asked to tap again
+	///
+	/// This only limits the retry loop. PC/SC has no way to interrupt an exchange that is already in progress, so a
+	/// single exchange with a stuck reader can still block for as long as the reader driver lets it.
+	pub fn set_retry_deadline(&mut self, retry_deadline: Option<Duration>) {
+		self.retry_deadline = retry_deadline;
+	}
+
+	/// Limits how many bytes of data (not counting the status bytes) a single response from the tag can hold
+	///
+	/// Reads of large tags and NDEF files are split into as many commands as needed to stay under the limit. Defaults
+	/// to what fits in `pcsc::MAX_BUFFER_SIZE`, which is also the most that can be set. Lower it for readers or
+	/// transports that can't pass through full-size responses.
+	pub fn set_max_response_size(&mut self, size: usize) {
+		self.max_response_size = size.min(pcsc::MAX_BUFFER_SIZE - 2);
+	}
+
+	/// Makes `get_user_id` also accept older badges that store the bare user ID in a Text record (off by default)
+	pub fn set_accept_text_uuids(&mut self, accept: bool) {
+		self.accept_text_uuids = accept;
+	}
+
+	/// Calls `handler` after every attempt at a tag command, including retries, so a UI can show something like
+	/// "reading… (attempt 2)" while a flaky tag is being read
+	pub fn set_progress_handler<F>(&mut self, handler: F)
+		where F: Fn(ReadProgress) + 'a
+	{
+		self.progress_handler = Some(Box::new(handler));
+	}
+
+	/// Starts or stops recording every APDU exchange made through the badge
+	///
+	/// A trace of a problematic scan can be replayed with `TraceReplay` to reproduce a parse or read failure without
+	/// the tag. Exchanges that fail at the PC/SC level aren't recorded. Stopping discards the recorded exchanges.
+	pub fn set_tracing(&mut self, enabled: bool) {
+		self.trace = if enabled { Some(RefCell::new(Vec::new())) } else { None };
+	}
+
+	/// Returns the exchanges recorded since tracing was enabled or the trace was last taken
+	pub fn take_trace(&self) -> Vec<ApduExchange> {
+		self.trace.as_ref()
+			.map(|trace| trace.replace(Vec::new()))
+			.unwrap_or_default()
+	}
+
 	pub fn get_user_id(&self) -> Result<String, Error> {
+		let record = self.read_first_record()?;
+		if self.accept_text_uuids && record.ndef_type == WellKnownType::Text {
+			if let Some(text) = record.get_content() {
+				let text = text.trim();
+				if is_uuid(text) {
+					return Ok(text.to_owned());
+				}
+			}
+		}
+		let url = NFCBadge::<T>::url_from_record(&record)?;
+		user_id_from_url(&url).ok_or_else(|| "URL did not contain user ID".into())
+	}
+
+	/// Reads the Secure Dynamic Messaging data an NTAG 424 DNA mirrors into the badge URL on every tap
+	///
+	/// Pass it on to whatever holds the tag keys to check that the badge is genuine. See `sdm_data_from_url` for the
+	/// URL parameters the tag has to be set up to mirror.
+	pub fn get_sdm_data(&self) -> Result<SDMData, Error> {
+		let url = self.get_badge_url()?;
+		sdm_data_from_url(&url).ok_or_else(|| "URL did not contain SDM data".into())
+	}
+
+	/// Reads the URL the badge points to from its first NDEF record
+	pub fn get_badge_url(&self) -> Result<Url, Error> {
+		NFCBadge::<T>::url_from_record(&self.read_first_record()?)
+	}
+
+	fn url_from_record(record: &NDEF) -> Result<Url, Error> {
+		let url = record.get_content().ok_or("NDEF message not URL")?;
+		Url::parse(&url).ok().ok_or_else(|| "Invalid URL".into())
+	}
+
+	fn read_first_record(&self) -> Result<NDEF, Error> {
+		let data = match self.read_ndef_memory() {
+			// The PN532 in a reader that has been idle for a while sometimes garbles its answer to the first tap
+			Err(Error::Message("Invalid PN532 response")) => {
+				thread::sleep(Duration::from_millis(50));
+				self.read_ndef_memory()?
+			},
+			data => data?,
+		};
+		Ok(NDEF::parse_ndef_area(&data)?)
+	}
+
+	/// Reads the raw NDEF message off of the badge without parsing it
+	///
+	/// Returns the value of the NDEF message TLV (everything after its length field up to the end of the message)
+	/// which is useful for forwarding to something that does its own parsing or for record types the parser doesn't
+	/// support yet
+	pub fn read_ndef_raw(&self) -> Result<Vec<u8>, Error> {
+		let data = self.read_ndef_memory()?;
+		let message = NDEF::find_message(&data).ok_or("No NDEF message found")?;
+		Ok(message.to_vec())
+	}
+
+	/// Returns how many bytes of the tag's NDEF data area are used and how many bytes it can hold in total
+	///
+	/// The capacity comes from the capability container (page 0x03) and the usage includes everything up to the end of
+	/// the NDEF message TLV. Useful for checking that new badge data will fit before writing it.
+	pub fn ndef_usage(&self) -> Result<(usize, usize), Error> {
+		let capacity = self.capacity()?;
+		let data = self.read_user_memory()?;
+		let used = NDEF::find_message_range(&data).map(|range| range.end).unwrap_or(0);
+		Ok((used, capacity))
+	}
+
+	/// The size of the tag's NDEF data area in bytes, read from the capability container (page 0x03)
+	///
+	/// Only reads a single page so it's a cheap check before provisioning a badge. Fails for tags with an unsupported
+	/// Type 2 Tag mapping version since their size can't be trusted.
+	pub fn capacity(&self) -> Result<usize, Error> {
+		let capability_container = self.read_capability_container()?;
+		if !capability_container.is_supported_version() {
+			return Err(Error::Message("Unsupported NFC Forum Type 2 Tag version"));
+		}
+		Ok(capability_container.data_area_size)
+	}
+
+	/// Reads and decodes the capability container (page 0x03) without checking its version
+	///
+	/// Useful for rejecting batches of non-compliant tags during provisioning QA
+	pub fn read_capability_container(&self) -> Result<CapabilityContainer, Error> {
+		let data = self.communicate_thru(&[0x3A, 0x03, 0x03])?;
+		// The magic number marks the tag as NDEF formatted
+		if data.len() < 4 || data[0] != 0xE1 {
+			return Err(Error::Message("Tag is not NDEF formatted"));
+		}
+		Ok(CapabilityContainer {
+			major_version: data[1] >> 4,
+			minor_version: data[1] & 0x0F,
+			// Given in units of 8 bytes
+			data_area_size: data[2] as usize * 8,
+			read_only: data[3] & 0x0F != 0,
+		})
+	}
+
+	/// Permanently makes the badge read-only by setting the static lock bytes (page 0x02 bytes 2 and 3) and the dynamic
+	/// lock bytes (the page after the last user page)
+	///
+	/// **This can't be undone.** Call it only after the badge's NDEF data has been written and verified. The static
+	/// lock bits cover the capability container and pages 0x04 to 0x0F while the dynamic lock bits cover the rest of
+	/// user memory, which is where the user ID at the end of the badge URL lives.
+	///
+	/// Checks that the lock took effect by rewriting the first and last user pages with their current contents, which
+	/// the tag must reject. Errors other than the tag rejecting those writes (e.g. the badge being lifted off the
+	/// reader) are returned since they don't show that the badge is locked.
+	pub fn lock_pages(&self) -> Result<(), Error> {
+		let (first_page, last_page) = self.get_tag_model()?.user_pages();
+		// Lock bits can only be set, so the tag ignores bits that don't apply to its size and the RFUI byte
+		self.write_page(last_page + 1, [0xFF, 0xFF, 0xFF, 0x00])?;
+		self.write_page(0x02, [0x00, 0x00, 0xFF, 0xFF])?;
+
+		for page in [first_page, last_page] {
+			let data = self.communicate_thru(&[0x3A, page, page])?;
+			if data.len() < 4 {
+				return Err(Error::Message(TAG_REMOVED_MESSAGE));
+			}
+			match self.write_page(page, [data[0], data[1], data[2], data[3]]) {
+				Ok(()) => return Err(Error::Message("Tag did not lock")),
+				Err(Error::Message(TAG_REJECTED_WRITE_MESSAGE)) => {},
+				Err(err) => return Err(err),
+			}
+		}
+		Ok(())
+	}
+
+	/// Reads the provisioning version counter kept in the tag's last user page
+	///
+	/// Provisioning stations can use it for optimistic concurrency: read the version, write the badge, and then only
+	/// bump the version with `write_provision_version` if it hasn't changed in the meantime. Blank tags read as 0.
+	pub fn read_provision_version(&self) -> Result<u32, Error> {
+		let page = self.provision_version_page()?;
+		let data = self.communicate_thru(&[0x3A, page, page])?;
+		if data.len() < 4 {
+			return Err(Error::Message(TAG_REMOVED_MESSAGE));
+		}
+		Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+	}
+
+	/// Writes the provisioning version counter to the tag's last user page
+	///
+	/// The page is part of the NDEF data area, so this fails instead of overwriting the end of an NDEF message that
+	/// runs into it
+	pub fn write_provision_version(&self, version: u32) -> Result<(), Error> {
+		let (used, capacity) = self.ndef_usage()?;
+		if used + 4 > capacity {
+			return Err(Error::Message("NDEF message overlaps the provision version page"));
+		}
+		self.write_page(self.provision_version_page()?, version.to_be_bytes())
+	}
+
+	/// Fails instead of guessing for tags whose model is unknown so the counter never lands inside the NDEF message
+	fn provision_version_page(&self) -> Result<u8, Error> {
+		Ok(self.get_tag_model()?.user_pages().1)
+	}
+
+	/// Writes a single four byte page with the WRITE (0xA2) command
+	///
+	/// The tag answers with a 4-bit ACK (0x0A) or NAK. Writing page 0x02 only changes the lock bytes; the tag ignores
+	/// the first two bytes since they are part of the serial number.
+	fn write_page(&self, page: u8, data: [u8; 4]) -> Result<(), Error> {
+		let response = self.communicate_thru(&[0xA2, page, data[0], data[1], data[2], data[3]])?;
+		match response.as_slice() {
+			[] | [0x0A] => Ok(()),
+			_ => Err(Error::Message(TAG_REJECTED_WRITE_MESSAGE)),
+		}
+	}
+
+	/// Gets the tag's UID and works out which family of tag it is
+	///
+	/// The ATQA and SAK come from re-running the PN532's InListPassiveTarget, which only works on readers built around
+	/// a PN532. Otherwise the family is worked out from the ATR.
+	pub fn get_tag_info(&self) -> Result<TagInfo, Error> {
+		let uid = self.send_data(&[0xFF, 0xCA, 0x00, 0x00, 0x00])?.data;
+
+		let mut atqa = None;
+		let mut sak = None;
+		if self.reader_model == ReaderModel::ACR122U {
+			// Response: 0xD5, 0x4B, number of targets, target number, ATQA (2 bytes), SAK, UID length, UID
+			if let Ok(response) = self.send_data(&[0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x4A, 0x01, 0x00]) {
+				let data = response.data;
+				if data.len() >= 7 && data.starts_with(&[0xD5, 0x4B]) && data[2] > 0 {
+					atqa = Some([data[4], data[5]]);
+					sak = Some(data[6]);
+				}
+			}
+		}
+
+		let family = match (sak, atqa) {
+			(Some(sak), Some(atqa)) => TagFamily::from_sak_atqa(sak, atqa),
+			_ => self.card.atr().map(|atr| TagFamily::from_atr(&atr)).unwrap_or(TagFamily::Unknown),
+		};
+		Ok(TagInfo { uid, atqa, sak, family })
+	}
+
+	/// Asks the tag which NTAG21x model it is using the GET_VERSION (0x60) command
+	///
+	/// The answer is remembered so only the first call exchanges anything with the tag. Tags that reject GET_VERSION
+	/// or aren't a known NTAG21x fail with `Error::Message("Unsupported tag model")`.
+	pub fn get_tag_model(&self) -> Result<TagModel, Error> {
+		let model = match self.tag_model.get() {
+			Some(model) => model,
+			None => {
+				let model = TagModel::from_version(&self.communicate_thru(&[0x60])?);
+				self.tag_model.set(Some(model));
+				model
+			},
+		};
+		model.ok_or(Error::Message(UNSUPPORTED_TAG_MODEL_MESSAGE))
+	}
+
+	/// Whether the tag is an ISO-DEP (ISO 14443-4) target, like a phone emulating a badge, instead of an NTAG
+	///
+	/// Worked out from the ATR (see `TagFamily::from_atr`) so it doesn't cost an exchange with the tag
+	pub fn is_iso_dep(&self) -> bool {
+		match self.card.atr() {
+			Some(atr) => TagFamily::from_atr(&atr) == TagFamily::ISODep,
+			None => false,
+		}
+	}
+
+	/// Reads the tag's NDEF data laid out like Type 2 tag memory (TLV blocks)
+	///
+	/// This is the NDEF data area only: reader framing (like the PN532's response code and status byte) and the
+	/// capability container are never included, so the result can go straight into `NDEF::parse_ndef_area`.
+	///
+	/// ISO-DEP targets are read through the Type 4 NDEF application and their message is wrapped in an NDEF message TLV
+	/// so that both kinds of tags can be parsed the same way. Type 5 tags already store TLV blocks after their
+	/// capability container.
+	fn read_ndef_memory(&self) -> Result<Vec<u8>, Error> {
+		match self.card.atr().map(|atr| TagFamily::from_atr(&atr)) {
+			Some(TagFamily::ISODep) => {},
+			Some(TagFamily::ISO15693) => return self.read_type5_memory(),
+			_ => return self.read_user_memory(),
+		}
+		let message = self.read_type4_ndef()?;
+		let length = message.len();
+		let mut data = Vec::with_capacity(length + 5);
+		if length < 0xFF {
+			data.extend_from_slice(&[0x03, length as u8]);
+		}
+		else {
+			data.extend_from_slice(&[0x03, 0xFF, (length >> 8) as u8, length as u8]);
+		}
+		data.extend_from_slice(&message);
+		data.push(0xFE);
+		Ok(data)
+	}
+
+	/// Reads the NDEF message from a Type 4 tag with the NDEF Tag Application's SELECT and READ BINARY commands
+	///
+	/// These are regular ISO 7816-4 APDUs that the reader passes through to the ISO-DEP target
+	fn read_type4_ndef(&self) -> Result<Vec<u8>, Error> {
+		// NDEF Tag Application version 2.0
+		self.send_data(&[0x00, 0xA4, 0x04, 0x00, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01, 0x00])?;
+		// Capability container file
+		self.send_data(&[0x00, 0xA4, 0x00, 0x0C, 0x02, 0xE1, 0x03])?;
+		let capability_container = self.send_data(&[0x00, 0xB0, 0x00, 0x00, 0x0F])?.data;
+		// The NDEF file control TLV (T = 0x04) follows the CC length, mapping version, MLe, and MLc
+		if capability_container.len() < 15 || capability_container[7] != 0x04 {
+			return Err(Error::Message("Tag is not NDEF formatted"));
+		}
+		// Short READ BINARY commands can't ask for more than 255 bytes
+		let max_read = (u16::from_be_bytes([capability_container[3], capability_container[4]]) as usize)
+			.min(self.max_response_size)
+			.clamp(1, 0xFF);
+		let file_id = [capability_container[9], capability_container[10]];
+
+		self.send_data(&[0x00, 0xA4, 0x00, 0x0C, 0x02, file_id[0], file_id[1]])?;
+		let length = self.send_data(&[0x00, 0xB0, 0x00, 0x00, 0x02])?.data;
+		if length.len() < 2 {
+			return Err(Error::Message("Invalid NDEF file"));
+		}
+		let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+
+		let mut message = Vec::with_capacity(length);
+		while message.len() < length {
+			// The message starts after the two byte length
+			let offset = message.len() + 2;
+			let chunk_length = max_read.min(length - message.len());
+			let chunk = self.send_data(&[0x00, 0xB0, (offset >> 8) as u8, offset as u8, chunk_length as u8])?.data;
+			if chunk.is_empty() {
+				return Err(Error::Message(TAG_REMOVED_MESSAGE));
+			}
+			message.extend_from_slice(&chunk);
+		}
+		message.truncate(length);
+		Ok(message)
+	}
+
+	/// Reads the data area of a Type 5 (ISO 15693) tag that follows its capability container, stopping early once the
+	/// whole NDEF message has been read
+	///
+	/// Uses the PC/SC part 3 Read Binary command, which readers that support ISO 15693 pass through to the tag as READ
+	/// MULTIPLE BLOCKS
+	fn read_type5_memory(&self) -> Result<Vec<u8>, Error> {
+		const BLOCK_SIZE: usize = 4;
+		const MAX_BLOCKS_PER_READ: usize = 32;
+		let read_blocks = |block: usize, count: usize| -> Result<Vec<u8>, Error> {
+			let length = count * BLOCK_SIZE;
+			let mut data = self.send_data(&[0xFF, 0xB0, (block >> 8) as u8, block as u8, length as u8])?.data;
+			if data.len() < length {
+				return Err(Error::Message(TAG_REMOVED_MESSAGE));
+			}
+			data.truncate(length);
+			Ok(data)
+		};
+
+		// Magic number, version and access, MLEN (the data area size divided by 8), and feature flags
+		let capability_container = read_blocks(0, 1)?;
+		if capability_container[0] != 0xE1 && capability_container[0] != 0xE2 {
+			return Err(Error::Message("Tag is not NDEF formatted"));
+		}
+		// Tags with more than 2040 bytes set MLEN to zero and use an eight byte capability container instead
+		let (first_block, data_area_size) = match capability_container[2] {
+			0x00 => {
+				let extended = read_blocks(1, 1)?;
+				(2, u16::from_be_bytes([extended[2], extended[3]]) as usize * 8)
+			},
+			size => (1, size as usize * 8),
+		};
+		if data_area_size < BLOCK_SIZE {
+			return Err(Error::Message("Tag is not NDEF formatted"));
+		}
+		let last_block = first_block + data_area_size / BLOCK_SIZE - 1;
+		let max_blocks_per_read = (self.max_response_size / BLOCK_SIZE).clamp(1, MAX_BLOCKS_PER_READ);
+
+		let mut data = Vec::new();
+		let mut start_block = first_block;
+		// The block holding the last byte of the NDEF message, once known
+		let mut message_end_block: Option<usize> = None;
+		loop {
+			let end_block = last_block
+				.min(start_block + max_blocks_per_read - 1)
+				.min(message_end_block.unwrap_or(last_block));
+			let chunk = read_blocks(start_block, end_block - start_block + 1)?;
+			data.extend_from_slice(&chunk);
+			if message_end_block.is_none() {
+				message_end_block = NDEF::message_tlv_header(&data).map(|(start, length)| {
+					let end = (start + length).max(1) - 1;
+					(first_block + end / BLOCK_SIZE).min(last_block)
+				});
+			}
+			let finished = match message_end_block {
+				Some(block) => end_block >= block,
+				None => chunk.contains(&0xFE),
+			};
+			if finished || end_block == last_block {
+				return Ok(data);
+			}
+			start_block = end_block + 1;
+		}
+	}
+
+	/// Reads the user memory area of the tag, stopping early once the whole NDEF message has been read
+	fn read_user_memory(&self) -> Result<Vec<u8>, Error> {
 		/*
 		Finally figured some cool stuff out:
 
@@ -74,39 +816,248 @@ impl NFCBadge<'_> {
 
 		This Stack Overflow answer has more related information:
 		https://stackoverflow.com/questions/44237726/how-to-authenticate-ntag213-with-acr122u/44243037#44243037
+
+		The NTAG215 and NTAG216 have much more user memory than the reader can return in a single response so
+		larger tags are read in chunks. The reads never go past the last user page which keeps the dynamic lock
+		bytes and configuration pages that follow it out of the NDEF data. Once the NDEF TLV's length field has been
+		read, only the pages that hold the rest of the message are fetched.
 		*/
-		const START_PAGE: u8 = 0x04; // 0x00 through 0x03 contain tag-related info. User data starts at 0x04
-		const END_PAGE: u8 = 0x27; // 0x27 is the last data page on the NTAG213
-		let apdu = [0xFF, 0x00, 0x00, 0x00, 0x05, 0xD4, 0x42, 0x3A, START_PAGE, END_PAGE];
-		let response = self.send_data(&apdu)?;
+		const MAX_PAGES_PER_READ: u8 = 36; // The full NTAG213 user area, known to fit in a single ACR122U response
+		// Leave room for the PN532's response code and status byte
+		let max_pages_per_read = (self.max_response_size.saturating_sub(3) / 4).clamp(1, MAX_PAGES_PER_READ as usize) as u8;
+		// Tags that NAK GET_VERSION get the original NTAG213 read range, but a failed exchange mustn't pass a larger
+		// tag off as an NTAG213 and cut its message short
+		let model = match self.get_tag_model() {
+			Err(Error::Message(UNSUPPORTED_TAG_MODEL_MESSAGE)) => TagModel::NTAG213,
+			model => model?,
+		};
+		let (first_page, last_page) = model.user_pages();
 
-		if &response.data[0..3] != [0xD5, 0x43, 0x00] {
-			return Err(Error::Message("Invalid PN532 response"));
+		let mut data = Vec::new();
+		let mut start_page = first_page;
+		// The page holding the last byte of the NDEF message, once known
+		let mut message_end_page: Option<u8> = None;
+		loop {
+			let end_page = last_page
+				.min(start_page.saturating_add(max_pages_per_read - 1))
+				.min(message_end_page.unwrap_or(last_page));
+			let chunk = self.communicate_thru(&[0x3A, start_page, end_page])?;
+			// The reader returns whatever it got if the tag leaves the field partway through
+			if chunk.len() < (end_page - start_page + 1) as usize * 4 {
+				return Err(Error::Message(TAG_REMOVED_MESSAGE));
+			}
+			data.extend_from_slice(&chunk);
+			if message_end_page.is_none() {
+				message_end_page = NDEF::message_tlv_header(&data).map(|(start, length)| {
+					let end = (start + length).max(1) - 1;
+					(first_page as usize + end / 4).min(last_page as usize) as u8
+				});
+			}
+			let finished = match message_end_page {
+				Some(page) => end_page >= page,
+				None => chunk.contains(&0xFE),
+			};
+			if finished || end_page == last_page {
+				return Ok(data);
+			}
+			start_page = end_page + 1;
 		}
-		let data = &response.data[3..];
-		let message = NDEF::parse(data)?;
-		let url = message.get_content().ok_or("NDEF message not URL")?;
-		let url = Url::parse(&url).ok().ok_or("Invalid URL")?;
+	}
+
+	/// Sends a raw tag command through the PN532's InCommunicateThru and returns the tag's response
+	///
+	/// Retried up to the configured number of times if the PN532 reports a transient error
+	fn communicate_thru(&self, command: &[u8]) -> Result<Vec<u8>, Error> {
+		let mut apdu = vec![0xFF, 0x00, 0x00, 0x00, command.len() as u8 + 2, 0xD4, 0x42];
+		apdu.extend_from_slice(command);
+
+		const TIMED_OUT: Error = Error::Message("Card read timed out");
+		let started = Instant::now();
+		let timed_out = || self.retry_deadline.is_some_and(|deadline| started.elapsed() >= deadline);
 
-		for keyvalue in url.query_pairs() {
-			match keyvalue.0 {
-				Cow::Borrowed("user") => return Ok(keyvalue.1.to_string()),
-				_ => {},
+		let mut attempt = 0;
+		loop {
+			let response = match self.send_data(&apdu) {
+				Err(Error::PCSC(pcsc::Error::Timeout)) => return Err(TIMED_OUT),
+				response => response?,
+			};
+			if !response.data.starts_with(&[0xD5, 0x43]) || response.data.len() < 3 {
+				return Err(Error::Message("Invalid PN532 response"));
+			}
+			if let Some(handler) = &self.progress_handler {
+				handler(ReadProgress { attempt: attempt + 1, status: response.data[2] });
+			}
+			match response.data[2] {
+				0x00 => return Ok(response.data[3..].to_vec()),
+				// Timeout, CRC, parity, bit count, framing, collision, and RF protocol errors
+				// These usually happen when the tag hasn't settled in the field yet
+				0x01..=0x06 | 0x0B if attempt < self.retries => {
+					if timed_out() {
+						return Err(TIMED_OUT);
+					}
+					attempt += 1;
+				},
+				_ => return Err(Error::Message("Invalid PN532 response")),
 			}
 		}
-		Err(Error::Message("URL did not contain user ID"))
 	}
 
+	/// Enables or disables the reader beeping when a tag is detected
 	pub fn set_buzzer(&self, enabled: bool) -> Result<bool, Error> {
-		let value = if enabled { 0xFF } else { 0x00 };
-		let apdu = [0xFF, 0x00, 0x52, value, 0x00];
-		self.send_data(&apdu)?;
+		match self.reader_model {
+			ReaderModel::ACR122U => {
+				let value = if enabled { 0xFF } else { 0x00 };
+				let apdu = [0xFF, 0x00, 0x52, value, 0x00];
+				self.send_data(&apdu)?;
+			},
+			ReaderModel::ACR1252U => {
+				// Default LED and buzzer behavior with bit 3 controlling the card insertion / removal beep
+				let behavior = if enabled { 0x8F } else { 0x87 };
+				self.send_escape(&[0xE0, 0x00, 0x00, 0x21, 0x01, behavior])?;
+			},
+		}
 		Ok(enabled)
 	}
 
+	/// Plays a beep pattern on the reader's buzzer so volunteers can tell successful and rejected scans apart by ear
+	///
+	/// Blocks until the pattern has finished playing
+	pub fn beep(&self, pattern: BeepPattern) -> Result<(), Error> {
+		match self.reader_model {
+			ReaderModel::ACR122U => {
+				// Leave the LEDs alone (no state mask bits) and sound the buzzer during T1 only
+				let apdu = [0xFF, 0x00, 0x40, 0x00, 0x04, pattern.on, pattern.off, pattern.repetitions, 0x01];
+				let response = self.card.transmit(&apdu)?;
+				if response.len() < 2 || response[response.len() - 2] != 0x90 {
+					return Err(Error::Message("Buzzer control failed"));
+				}
+			},
+			ReaderModel::ACR1252U => {
+				// The ACR1252U can only play a single beep (in units of 10 ms) so repeat it ourselves
+				let on = Duration::from_millis(pattern.on as u64 * 100);
+				let off = Duration::from_millis(pattern.off as u64 * 100);
+				for _ in 0..pattern.repetitions {
+					self.send_escape(&[0xE0, 0x00, 0x00, 0x28, 0x01, pattern.on.saturating_mul(10)])?;
+					thread::sleep(on + off);
+				}
+			},
+		}
+		Ok(())
+	}
+
+	/// Turns the reader's red and green LEDs on or off
+	pub fn set_led(&self, red: bool, green: bool) -> Result<(), Error> {
+		let state = red as u8 | (green as u8) << 1;
+		match self.reader_model {
+			ReaderModel::ACR122U => {
+				// Update both LEDs (state mask bits 2 and 3) with no blinking
+				let apdu = [0xFF, 0x00, 0x40, state | 0x0C, 0x04, 0x00, 0x00, 0x00, 0x00];
+				// The second status byte is the current LED state instead of 0x00
+				let response = self.card.transmit(&apdu)?;
+				if response.len() < 2 || response[response.len() - 2] != 0x90 {
+					return Err(Error::Message("LED control failed"));
+				}
+			},
+			ReaderModel::ACR1252U => {
+				self.send_escape(&[0xE0, 0x00, 0x00, 0x29, 0x01, state])?;
+			},
+		}
+		Ok(())
+	}
+
+	/// Flashes the reader's LEDs once for `duration` (in units of 100 ms) and then turns them off
+	///
+	/// The reader times the flash itself so nothing has to sleep while it's lit. Only the ACR122U supports this.
+	pub fn flash_led(&self, red: bool, green: bool, duration: u8) -> Result<(), Error> {
+		if self.reader_model != ReaderModel::ACR122U {
+			return Err(Error::Message("Reader does not support LED flashes"));
+		}
+		// Blink (bits 6 and 7) starting lit (bits 4 and 5) and update (bits 2 and 3) the chosen LEDs, ending with them off
+		let red_bits = if red { 0x54 } else { 0x00 };
+		let green_bits = if green { 0xA8 } else { 0x00 };
+		// Lit for T1, off for T2 (none), one repetition, and no buzzer
+		let apdu = [0xFF, 0x00, 0x40, red_bits | green_bits, 0x04, duration, 0x00, 0x01, 0x00];
+		let response = self.card.transmit(&apdu)?;
+		if response.len() < 2 || response[response.len() - 2] != 0x90 {
+			return Err(Error::Message("LED control failed"));
+		}
+		Ok(())
+	}
+
+	/// Turns the reader's RF field on or off
+	///
+	/// Keeping the field off between scans saves a lot of power on battery-powered handhelds. No tags can be detected
+	/// while the field is off. Uses the PN532's RFConfiguration command through the ACR122U's direct transmit
+	/// pseudo-APDU so it isn't supported on other readers.
+	pub fn set_field(&self, on: bool) -> Result<(), Error> {
+		// Configuration item 0x01 is the RF field with bit 0 turning it on (and bit 1, automatic RF collision avoidance, left off)
+		self.pn532_command(&[0x32, 0x01, on as u8])?;
+		Ok(())
+	}
+
+	/// Puts the reader's PN532 into its low power mode, which also turns off the RF field
+	///
+	/// The PN532 wakes up again on the next command sent to it. Only supported on the ACR122U, like `set_field`.
+	pub fn power_down(&self) -> Result<(), Error> {
+		// Wake up on activity on any of the host interfaces (I2C, SPI, and HSU)
+		let response = self.pn532_command(&[0x16, 0xB0])?;
+		match response.first() {
+			Some(0x00) => Ok(()),
+			_ => Err(Error::Message("PN532 failed to power down")),
+		}
+	}
+
+	/// Sends a command to the ACR122U's PN532 and returns the response data after the response code
+	fn pn532_command(&self, command: &[u8]) -> Result<Vec<u8>, Error> {
+		if self.reader_model != ReaderModel::ACR122U {
+			return Err(Error::Message("Reader does not support PN532 commands"));
+		}
+		let mut apdu = vec![0xFF, 0x00, 0x00, 0x00, command.len() as u8 + 1, 0xD4];
+		apdu.extend_from_slice(command);
+		let response = self.send_data(&apdu)?.data;
+		if !response.starts_with(&[0xD5, command[0] + 1]) {
+			return Err(Error::Message("Invalid PN532 response"));
+		}
+		Ok(response[2..].to_vec())
+	}
+
+	/// Asks the reader for its firmware version (e.g. `ACR122U215`)
+	///
+	/// Useful for keeping track of readers running firmware that is known to mishandle FAST_READ
+	pub fn get_reader_firmware(&self) -> Result<String, Error> {
+		let version = match self.reader_model {
+			ReaderModel::ACR122U => {
+				// Unlike other pseudo-APDUs the ACR122U answers this with just the version string and no status bytes
+				let mut response = self.card.transmit(&[0xFF, 0x00, 0x48, 0x00, 0x00])?;
+				if response.ends_with(&[0x90, 0x00]) {
+					response.truncate(response.len() - 2);
+				}
+				response
+			},
+			ReaderModel::ACR1252U => self.send_escape(&[0xE0, 0x00, 0x00, 0x18, 0x00])?,
+		};
+		if version.is_empty() {
+			return Err(Error::Message("Reader did not return a firmware version"));
+		}
+		Ok(String::from_utf8_lossy(&version).trim_end_matches('\0').to_string())
+	}
+
+	/// Sends an ACR1252U escape command and returns the response data
+	///
+	/// Note: escape commands have to be enabled in the CCID driver on Linux and macOS
+	fn send_escape(&self, command: &[u8]) -> Result<Vec<u8>, Error> {
+		let response = self.card.escape(command)?;
+		if !response.starts_with(&[0xE1, 0x00, 0x00, 0x00]) || response.len() < 5 {
+			return Err(Error::Message("Invalid reader escape response"));
+		}
+		Ok(response[5..].to_vec())
+	}
+
 	pub(crate) fn send_data(&self, apdu: &[u8]) -> Result<CardResponse, Error> {
-		let mut rapdu_buf = [0u8; pcsc::MAX_BUFFER_SIZE];
-		let mut rapdu = self.card.transmit(apdu, &mut rapdu_buf)?.to_vec();
+		let mut rapdu = match self.card.transmit(apdu) {
+			Err(pcsc::Error::RemovedCard) | Err(pcsc::Error::ResetCard) => return Err(Error::Message(TAG_REMOVED_MESSAGE)),
+			rapdu => rapdu?,
+		};
 
 		if rapdu.len() < 2 {
 			return Err(pcsc::Error::InvalidValue.into());
@@ -114,6 +1065,13 @@ impl NFCBadge<'_> {
 
 		let status = [rapdu[rapdu.len() - 2], rapdu[rapdu.len() - 1]];
 		rapdu.truncate(rapdu.len() - 2);
+		if let Some(trace) = &self.trace {
+			trace.borrow_mut().push(ApduExchange {
+				request: apdu.to_vec(),
+				response: rapdu.clone(),
+				status,
+			});
+		}
 		// APDU response of 0x90, 0x00 means command executing successfully
 		if status[0] == 0x90 && status[1] == 0x00 {
 			Ok(CardResponse {
@@ -126,3 +1084,709 @@ impl NFCBadge<'_> {
 		}
 	}
 }
+
+impl NFCBadge<'_, pcsc::Card> {
+	/// Runs a sequence of badge operations inside an exclusive PC/SC transaction
+	///
+	/// No other process can talk to the card until `operations` returns, so multi-command sequences (like writing and
+	/// then verifying data) can't be interleaved with another accessor and end up corrupted. For cards connected
+	/// outside of `handle_cards`, whose card handlers already run inside a transaction.
+	pub fn transaction<R, F>(card: &mut pcsc::Card, operations: F) -> Result<R, Error>
+		where F: FnOnce(&NFCBadge<pcsc::Card>) -> Result<R, Error>,
+	{
+		let transaction = card.transaction()?;
+		let result = operations(&NFCBadge::new(&transaction));
+		transaction.end(pcsc::Disposition::LeaveCard).map_err(|(_, err)| err)?;
+		result
+	}
+}
+
+/// Extracts the user ID from a badge URL
+///
+/// Current badges use a `user` query parameter (`https://live.hack.gt/?user=<uuid>`) while some older ones have the ID
+/// as the last path segment (`https://live.hack.gt/u/<uuid>`). QR codes shown instead of a badge encode the same URL.
+pub fn user_id_from_url(url: &Url) -> Option<String> {
+	for keyvalue in url.query_pairs() {
+		if let Cow::Borrowed("user") = keyvalue.0 {
+			return Some(keyvalue.1.to_string());
+		}
+	}
+	user_id_from_path(url)
+}
+
+/// Same as `user_id_from_url` but returns the `user` query parameter exactly as it appears in the URL without
+/// percent-decoding it
+///
+/// Use this when IDs were already percent-encoded upstream and decoding them again would change them
+pub fn user_id_from_url_raw(url: &Url) -> Option<String> {
+	let user = url.query()
+		.into_iter()
+		.flat_map(|query| query.split('&'))
+		.find_map(|pair| pair.strip_prefix("user="));
+	match user {
+		Some(user) => Some(user.to_string()),
+		None => user_id_from_path(url),
+	}
+}
+
+fn user_id_from_path(url: &Url) -> Option<String> {
+	let last_segment = url.path_segments()?.rev().find(|segment| !segment.is_empty())?;
+	if is_uuid(last_segment) {
+		Some(last_segment.to_string())
+	}
+	else {
+		None
+	}
+}
+
+/// The Secure Dynamic Messaging (SUN) values an NTAG 424 DNA writes into its URL each time it's read
+#[derive(Debug, Clone, PartialEq)]
+pub struct SDMData {
+	/// The tag's 7 byte UID
+	pub uid: Vec<u8>,
+	/// The SDM read counter, which goes up on every tap so a copied URL can't be replayed
+	pub counter: u32,
+	/// The truncated CMAC over the UID and counter
+	pub signature: Vec<u8>,
+}
+
+/// Extracts SDM data mirrored into a badge URL as the hex encoded `uid`, `ctr` (big endian), and `cmac` query parameters
+pub fn sdm_data_from_url(url: &Url) -> Option<SDMData> {
+	let mut uid = None;
+	let mut counter = None;
+	let mut signature = None;
+	for (key, value) in url.query_pairs() {
+		match key.as_ref() {
+			"uid" => uid = decode_hex(&value),
+			"ctr" if value.len() == 6 => counter = u32::from_str_radix(&value, 16).ok(),
+			"cmac" => signature = decode_hex(&value),
+			_ => {},
+		}
+	}
+	Some(SDMData { uid: uid?, counter: counter?, signature: signature? })
+}
+
+// `usize::is_multiple_of` needs a newer toolchain than anything else in the crate
+#[allow(clippy::manual_is_multiple_of)]
+fn decode_hex(value: &str) -> Option<Vec<u8>> {
+	if value.len() % 2 != 0 || !value.is_ascii() {
+		return None;
+	}
+	(0..value.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&value[i..i + 2], 16).ok())
+		.collect()
+}
+
+fn is_uuid(value: &str) -> bool {
+	value.len() == 36 && value.char_indices().all(|(i, c)| match i {
+		8 | 13 | 18 | 23 => c == '-',
+		_ => c.is_ascii_hexdigit(),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ CardTransport, NFCBadge, TagModel, TagFamily, ReaderModel, BeepPattern, TraceReplay, ApduExchange, Error, NDEF, user_id_from_url, user_id_from_url_raw, sdm_data_from_url };
+	use url::Url;
+	use std::ffi::CString;
+	use std::time::Duration;
+	use std::cell::{ Cell, RefCell };
+
+	/// Answers GET_VERSION, FAST_READ, and WRITE like an NTAG21x behind an ACR122U
+	struct SimulatedTag {
+		version: [u8; 8],
+		memory: RefCell<Vec<u8>>,
+		reads: RefCell<Vec<(u8, u8)>>,
+		/// Number of upcoming commands that will time out
+		timeouts: Cell<u32>,
+		/// Return partial FAST_READ responses past the end of `memory` like a tag leaving the field
+		truncate_reads: Cell<bool>,
+		/// Number of upcoming commands answered without a PN532 response like a reader that just woke up
+		garbled: Cell<u32>,
+		/// Number of WRITE commands that go through before the tag is lifted off the reader
+		writes_before_removal: Cell<Option<u32>>,
+	}
+	impl CardTransport for SimulatedTag {
+		fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+			assert_eq!(&apdu[0..4], &[0xFF, 0x00, 0x00, 0x00]);
+			assert_eq!(&apdu[5..7], &[0xD4, 0x42]);
+			let mut response = vec![0xD5, 0x43, 0x00];
+			if self.timeouts.get() > 0 {
+				self.timeouts.set(self.timeouts.get() - 1);
+				return Ok(vec![0xD5, 0x43, 0x01, 0x90, 0x00]);
+			}
+			if self.garbled.get() > 0 {
+				self.garbled.set(self.garbled.get() - 1);
+				return Ok(vec![0x90, 0x00]);
+			}
+			match apdu[7] {
+				0x60 => response.extend_from_slice(&self.version),
+				0x3A => {
+					let (start, end) = (apdu[8], apdu[9]);
+					self.reads.borrow_mut().push((start, end));
+					let memory = self.memory.borrow();
+					let end = if self.truncate_reads.get() { memory.len().min((end as usize + 1) * 4) } else { (end as usize + 1) * 4 };
+					response.extend_from_slice(&memory[start as usize * 4..end]);
+				},
+				0xA2 => {
+					match self.writes_before_removal.get() {
+						Some(0) => return Err(pcsc::Error::RemovedCard),
+						Some(writes) => self.writes_before_removal.set(Some(writes - 1)),
+						None => {},
+					}
+					let page = apdu[8] as usize;
+					let mut memory = self.memory.borrow_mut();
+					// Follows the last user page, five pages before the end of memory
+					let dynamic_lock_page = memory.len() / 4 - 5;
+					let locked = match page {
+						0..=2 => false,
+						3..=7 => memory[10] & 1 << page != 0,
+						8..=15 => memory[11] & 1 << (page - 8) != 0,
+						// Simplified: any dynamic lock bit locks the rest of user memory
+						page if page < dynamic_lock_page => memory[dynamic_lock_page * 4..dynamic_lock_page * 4 + 3] != [0, 0, 0],
+						_ => false,
+					};
+					if locked {
+						// NAK
+						response.push(0x00);
+					}
+					else if page == 2 {
+						// Lock bits can only be set
+						memory[10] |= apdu[11];
+						memory[11] |= apdu[12];
+						response.push(0x0A);
+					}
+					else if page == dynamic_lock_page {
+						for (byte, bits) in memory[page * 4..page * 4 + 3].iter_mut().zip(&apdu[9..12]) {
+							*byte |= bits;
+						}
+						response.push(0x0A);
+					}
+					else {
+						memory[page * 4..page * 4 + 4].copy_from_slice(&apdu[9..13]);
+						response.push(0x0A);
+					}
+				},
+				_ => response[2] = 0x01,
+			}
+			response.extend_from_slice(&[0x90, 0x00]);
+			Ok(response)
+		}
+	}
+
+	/// Answers the Type 4 NDEF application's commands like a phone in card emulation mode
+	struct SimulatedPhone {
+		/// The contents of the NDEF file: a two byte length followed by the message
+		ndef_file: Vec<u8>,
+		max_read: u16,
+		selected: Cell<[u8; 2]>,
+		reads: RefCell<Vec<usize>>,
+	}
+	impl SimulatedPhone {
+		fn with_url(url: &str, max_read: u16) -> Self {
+			let mut record = vec![0xD1, 0x01, url.len() as u8 + 1, 0x55, 0x04];
+			record.extend_from_slice(url.as_bytes());
+			SimulatedPhone::with_message(&record, max_read)
+		}
+
+		fn with_message(message: &[u8], max_read: u16) -> Self {
+			let mut ndef_file = (message.len() as u16).to_be_bytes().to_vec();
+			ndef_file.extend_from_slice(message);
+			SimulatedPhone { ndef_file, max_read, selected: Cell::new([0, 0]), reads: RefCell::new(Vec::new()) }
+		}
+	}
+	impl CardTransport for SimulatedPhone {
+		fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+			let mut response = match apdu[1] {
+				0xA4 if apdu[2] == 0x04 => Vec::new(),
+				0xA4 => {
+					self.selected.set([apdu[5], apdu[6]]);
+					Vec::new()
+				},
+				0xB0 => {
+					let offset = u16::from_be_bytes([apdu[2], apdu[3]]) as usize;
+					let length = apdu[4] as usize;
+					let file = match self.selected.get() {
+						[0xE1, 0x03] => {
+							let max_read = self.max_read.to_be_bytes();
+							vec![0x00, 0x0F, 0x20, max_read[0], max_read[1], 0x00, 0xFF, 0x04, 0x06, 0xE1, 0x04, 0x10, 0x00, 0x00, 0xFF]
+						},
+						[0xE1, 0x04] => {
+							assert!(length <= self.max_read as usize);
+							self.reads.borrow_mut().push(length);
+							self.ndef_file.clone()
+						},
+						_ => return Ok(vec![0x6A, 0x82]),
+					};
+					file[offset..(offset + length).min(file.len())].to_vec()
+				},
+				_ => return Ok(vec![0x6D, 0x00]),
+			};
+			response.extend_from_slice(&[0x90, 0x00]);
+			Ok(response)
+		}
+
+		fn atr(&self) -> Option<Vec<u8>> {
+			// ISO-DEP ATR with the historical bytes of a phone's emulated card
+			Some(vec![0x3B, 0x80, 0x80, 0x01, 0x01])
+		}
+	}
+
+	fn ntag215_with_url(url: &str) -> SimulatedTag {
+		let mut record = vec![0xD1, 0x01, url.len() as u8 + 1, 0x55, 0x04];
+		record.extend_from_slice(url.as_bytes());
+		ntag215_with_record(record)
+	}
+
+	fn ntag215_with_record(record: Vec<u8>) -> SimulatedTag {
+		// 135 pages: user memory ends at 0x81, followed by the dynamic lock bytes and configuration pages
+		let mut memory = vec![0u8; 135 * 4];
+		memory[3 * 4..4 * 4].copy_from_slice(&[0xE1, 0x10, 0x3E, 0x00]);
+		let mut tlv = vec![0x03, record.len() as u8];
+		tlv.extend_from_slice(&record);
+		tlv.push(0xFE);
+		memory[4 * 4..4 * 4 + tlv.len()].copy_from_slice(&tlv);
+		memory[0x82 * 4..0x82 * 4 + 4].copy_from_slice(&[0x00, 0x00, 0x00, 0xBD]);
+		for byte in &mut memory[0x83 * 4..] {
+			*byte = 0xD1;
+		}
+		SimulatedTag {
+			version: [0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03],
+			memory: RefCell::new(memory),
+			reads: RefCell::new(Vec::new()),
+			timeouts: Cell::new(0),
+			truncate_reads: Cell::new(false),
+			garbled: Cell::new(0),
+			writes_before_removal: Cell::new(None),
+		}
+	}
+
+	#[test]
+	fn ntag215_read_skips_lock_bytes() {
+		let padding = "x".repeat(160);
+		let url = format!("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e&padding={}", padding);
+		let tag = ntag215_with_url(&url);
+		let badge = NFCBadge::new(&tag);
+
+		assert_eq!(badge.get_tag_model().unwrap(), TagModel::NTAG215);
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+
+		let reads = tag.reads.borrow();
+		assert!(reads.len() > 1, "NDEF message should span more than one FAST_READ");
+		assert!(reads.iter().all(|&(_, end)| end <= 0x81), "Read past user memory: {:x?}", reads);
+	}
+
+	#[test]
+	fn read_ndef_raw() {
+		let url = "live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+		let tag = ntag215_with_url(url);
+		let raw = NFCBadge::new(&tag).read_ndef_raw().unwrap();
+
+		assert_eq!(raw.len(), url.len() + 5);
+		assert_eq!(&raw[0..5], &[0xD1, 0x01, url.len() as u8 + 1, 0x55, 0x04]);
+		assert_eq!(&raw[5..], url.as_bytes());
+	}
+
+	#[test]
+	fn read_stops_at_end_of_message() {
+		// 2 byte TLV header + 229 byte record ends in page 0x3D
+		let url = format!("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e&padding={}", "x".repeat(160));
+		let tag = ntag215_with_url(&url);
+		NFCBadge::new(&tag).read_ndef_raw().unwrap();
+		assert_eq!(*tag.reads.borrow(), [(0x04, 0x27), (0x28, 0x3D)]);
+	}
+
+	#[test]
+	fn retries_transient_pn532_errors() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let badge = NFCBadge::new(&tag);
+
+		tag.timeouts.set(2);
+		assert_eq!(badge.get_tag_model().unwrap(), TagModel::NTAG215);
+
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_retries(1);
+		tag.timeouts.set(2);
+		assert!(badge.get_tag_model().is_err());
+	}
+
+	#[test]
+	fn tag_model_lookup() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_tracing(true);
+		badge.read_ndef_raw().unwrap();
+		badge.ndef_usage().unwrap();
+		let get_versions = badge.take_trace().iter().filter(|exchange| exchange.request[7] == 0x60).count();
+		assert_eq!(get_versions, 1);
+
+		// A failed GET_VERSION exchange isn't taken to mean the tag is an NTAG213
+		let badge = NFCBadge::new(&tag);
+		tag.garbled.set(1);
+		assert!(matches!(badge.read_ndef_raw(), Err(Error::Message("Invalid PN532 response"))));
+
+		// Tags that don't identify as an NTAG21x are read like an NTAG213
+		let mut tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		tag.version = [0x00; 8];
+		NFCBadge::new(&tag).read_ndef_raw().unwrap();
+		assert_eq!(*tag.reads.borrow(), [(0x04, 0x27)]);
+	}
+
+	#[test]
+	fn progress_handler() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let progress = RefCell::new(Vec::new());
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_progress_handler(|update| progress.borrow_mut().push((update.attempt, update.status)));
+
+		tag.timeouts.set(2);
+		badge.get_tag_model().unwrap();
+		drop(badge);
+		assert_eq!(progress.into_inner(), [(1, 0x01), (2, 0x01), (3, 0x00)]);
+	}
+
+	#[test]
+	fn retries_cold_reader() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let badge = NFCBadge::new(&tag);
+
+		// GET_VERSION is garbled on the first attempt
+		tag.garbled.set(1);
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+
+		// Only one retry
+		let badge = NFCBadge::new(&tag);
+		tag.garbled.set(2);
+		assert!(badge.get_user_id().is_err());
+	}
+
+	/// Records APDUs sent to the reader itself and acknowledges them like an ACR122U
+	struct RecordingReader {
+		apdus: RefCell<Vec<Vec<u8>>>,
+	}
+	impl CardTransport for RecordingReader {
+		fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+			self.apdus.borrow_mut().push(apdu.to_vec());
+			Ok(vec![0x90, 0x00])
+		}
+	}
+
+	#[test]
+	fn beep_pattern() {
+		let reader = RecordingReader { apdus: RefCell::new(Vec::new()) };
+		let badge = NFCBadge::new(&reader);
+		badge.beep(BeepPattern::SUCCESS).unwrap();
+		badge.beep(BeepPattern::FAILURE).unwrap();
+		assert_eq!(*reader.apdus.borrow(), [
+			vec![0xFF, 0x00, 0x40, 0x00, 0x04, 0x01, 0x00, 0x01, 0x01],
+			vec![0xFF, 0x00, 0x40, 0x00, 0x04, 0x04, 0x02, 0x02, 0x01],
+		]);
+	}
+
+	#[test]
+	fn flash_led() {
+		let reader = RecordingReader { apdus: RefCell::new(Vec::new()) };
+		let mut badge = NFCBadge::new(&reader);
+		badge.flash_led(true, false, 5).unwrap();
+		assert_eq!(*reader.apdus.borrow(), [vec![0xFF, 0x00, 0x40, 0x54, 0x04, 0x05, 0x00, 0x01, 0x00]]);
+
+		badge.set_reader_model(ReaderModel::ACR1252U);
+		assert!(badge.flash_led(true, false, 5).is_err());
+	}
+
+	#[test]
+	fn reader_model_from_name() {
+		let name = CString::new("ACS ACR1252 1S CL Reader [ACR1252 1S CL Reader(1)] 00 00").unwrap();
+		assert_eq!(ReaderModel::from_name(&name), ReaderModel::ACR1252U);
+		let name = CString::new("ACS ACR122U PICC Interface 00 00").unwrap();
+		assert_eq!(ReaderModel::from_name(&name), ReaderModel::ACR122U);
+	}
+
+	#[test]
+	fn ndef_usage() {
+		let url = "live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+		let tag = ntag215_with_url(url);
+		let (used, capacity) = NFCBadge::new(&tag).ndef_usage().unwrap();
+
+		assert_eq!(used, 2 + 5 + url.len());
+		assert_eq!(capacity, 496);
+		assert_eq!(NFCBadge::new(&tag).capacity().unwrap(), 496);
+		assert_eq!(*tag.reads.borrow().last().unwrap(), (0x03, 0x03));
+	}
+
+	#[test]
+	fn lock_pages() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let badge = NFCBadge::new(&tag);
+		badge.lock_pages().unwrap();
+		assert_eq!(&tag.memory.borrow()[0x0A..0x0C], &[0xFF, 0xFF]);
+		assert_eq!(&tag.memory.borrow()[0x82 * 4..0x82 * 4 + 3], &[0xFF, 0xFF, 0xFF]);
+		assert!(badge.write_page(0x04, [0x03, 0x00, 0xFE, 0x00]).is_err());
+		// The end of the badge URL is past the pages covered by the static lock bits
+		assert!(badge.write_page(0x10, [0x00, 0x00, 0x00, 0x00]).is_err());
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+
+		// Lifting the badge off before the lock is verified isn't mistaken for the tag refusing the write
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		tag.writes_before_removal.set(Some(2));
+		assert!(NFCBadge::new(&tag).lock_pages().unwrap_err().is_tag_removed());
+	}
+
+	#[test]
+	fn text_uuid_badge() {
+		let url_badge = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let mut record = vec![0xD1, 0x01, 39, 0x54, 0x02, b'e', b'n'];
+		record.extend_from_slice(b"7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let text_badge = ntag215_with_record(record);
+
+		assert!(NFCBadge::new(&text_badge).get_user_id().is_err());
+		for tag in [&url_badge, &text_badge] {
+			let mut badge = NFCBadge::new(tag);
+			badge.set_accept_text_uuids(true);
+			assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		}
+	}
+
+	#[test]
+	fn capability_container_version() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let badge = NFCBadge::new(&tag);
+		let capability_container = badge.read_capability_container().unwrap();
+		assert_eq!((capability_container.major_version, capability_container.minor_version), (1, 0));
+		assert!(!capability_container.read_only);
+
+		tag.memory.borrow_mut()[3 * 4 + 1] = 0x20;
+		assert!(!badge.read_capability_container().unwrap().is_supported_version());
+		assert!(badge.capacity().is_err());
+	}
+
+	#[test]
+	fn provision_version() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let badge = NFCBadge::new(&tag);
+		assert_eq!(badge.read_provision_version().unwrap(), 0);
+		badge.write_provision_version(7).unwrap();
+		assert_eq!(&tag.memory.borrow()[0x81 * 4..0x82 * 4], &[0x00, 0x00, 0x00, 0x07]);
+		assert_eq!(badge.read_provision_version().unwrap(), 7);
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+
+		// A message that fills the whole data area would be cut short
+		let url = format!("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e&padding={}", "x".repeat(180));
+		let tag = ntag215_with_url(&url);
+		tag.memory.borrow_mut()[3 * 4 + 2] = 0x1A;
+		assert!(NFCBadge::new(&tag).write_provision_version(1).is_err());
+
+		// The counter page isn't guessed when GET_VERSION fails
+		tag.garbled.set(1);
+		assert!(NFCBadge::new(&tag).read_provision_version().is_err());
+	}
+
+	#[test]
+	fn tag_removed_mid_read() {
+		let url = format!("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e&padding={}", "x".repeat(160));
+		let mut tag = ntag215_with_url(&url);
+		// Memory stops answering partway through the second chunk
+		tag.memory.get_mut().truncate(0x30 * 4);
+		tag.truncate_reads.set(true);
+		let err = NFCBadge::new(&tag).get_user_id().unwrap_err();
+		assert!(err.is_tag_removed());
+	}
+
+	#[test]
+	fn phone_card_emulation() {
+		let phone = SimulatedPhone::with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e", 0x20);
+		let badge = NFCBadge::new(&phone);
+		assert!(badge.is_iso_dep());
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		// The 60 byte message is read in chunks of at most MLe bytes
+		assert_eq!(*phone.reads.borrow(), [2, 32, 28]);
+	}
+
+	#[test]
+	fn user_id_with_app_record() {
+		let tlv = NDEF::encode_message(&[
+			NDEF::encode_uri("https://live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e"),
+			NDEF::encode_aar("gt.hack.companion"),
+		]);
+		let phone = SimulatedPhone::with_message(NDEF::find_message(&tlv).unwrap(), 0xFF);
+		assert_eq!(NFCBadge::new(&phone).get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+
+		let mut memory = vec![0xE1, 0x40, 0x20, 0x01];
+		memory.extend_from_slice(&tlv);
+		memory.resize(4 + 256, 0x00);
+		let tag = SimulatedType5 { memory, reads: RefCell::new(Vec::new()) };
+		assert_eq!(NFCBadge::new(&tag).get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+	}
+
+	#[test]
+	fn large_reads_are_split() {
+		// A 1 KB NDEF file with a long URI record
+		let url = format!("https://live.hack.gt/u/7dd00021-89fd-49f1-9c17-bd0ba7dcf97e?padding={}", "x".repeat(950));
+		let tlv = NDEF::encode_message(&[NDEF::encode_uri(&url)]);
+		let message = NDEF::find_message(&tlv).unwrap().to_vec();
+		assert!(message.len() > 1000);
+
+		let phone = SimulatedPhone::with_message(&message, 0xFF);
+		let mut badge = NFCBadge::new(&phone);
+		assert_eq!(badge.read_ndef_raw().unwrap(), message);
+		assert!(phone.reads.borrow()[1..].iter().all(|&length| length <= 0xFF));
+		phone.reads.borrow_mut().clear();
+
+		badge.set_max_response_size(100);
+		assert_eq!(badge.read_ndef_raw().unwrap(), message);
+		let reads = phone.reads.borrow();
+		assert!(reads.iter().all(|&length| length <= 100), "{:?}", reads);
+		assert_eq!(reads[1..].iter().sum::<usize>(), message.len());
+
+		let tag = ntag215_with_url(&"x".repeat(200));
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_max_response_size(64);
+		assert_eq!(NDEF::find_uri(&badge.read_ndef_memory().unwrap()).unwrap(), format!("https://{}", "x".repeat(200)));
+		// 15 pages (60 bytes) plus the PN532 header fit in 64 bytes
+		let reads = tag.reads.borrow();
+		assert!(reads.iter().all(|&(start, end)| end - start < 15), "{:x?}", reads);
+	}
+
+	#[test]
+	fn replay_trace() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_tracing(true);
+		badge.get_user_id().unwrap();
+		let trace = badge.take_trace();
+		assert_eq!(trace.len(), 2);
+		assert_eq!(trace[0].request, [0xFF, 0x00, 0x00, 0x00, 0x03, 0xD4, 0x42, 0x60]);
+		assert!(badge.take_trace().is_empty());
+
+		let replay = TraceReplay::new(trace);
+		assert_eq!(NFCBadge::new(&replay).get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		assert!(replay.finished());
+		assert!(NFCBadge::new(&replay).get_tag_model().is_err());
+	}
+
+	#[test]
+	fn field_control() {
+		let exchange = |request: &[u8], response: &[u8]| ApduExchange {
+			request: request.to_vec(),
+			response: response.to_vec(),
+			status: [0x90, 0x00],
+		};
+		let replay = TraceReplay::new(vec![
+			exchange(&[0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x32, 0x01, 0x00], &[0xD5, 0x33]),
+			exchange(&[0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x32, 0x01, 0x01], &[0xD5, 0x33]),
+			exchange(&[0xFF, 0x00, 0x00, 0x00, 0x03, 0xD4, 0x16, 0xB0], &[0xD5, 0x17, 0x00]),
+		]);
+		let mut badge = NFCBadge::new(&replay);
+		badge.set_field(false).unwrap();
+		badge.set_field(true).unwrap();
+		badge.power_down().unwrap();
+		assert!(replay.finished());
+
+		badge.set_reader_model(ReaderModel::ACR1252U);
+		assert!(badge.set_field(true).is_err());
+	}
+
+	#[test]
+	fn tag_family() {
+		assert_eq!(TagFamily::from_sak_atqa(0x00, [0x00, 0x44]), TagFamily::Ultralight);
+		assert_eq!(TagFamily::from_sak_atqa(0x08, [0x00, 0x04]), TagFamily::MifareClassic);
+		assert_eq!(TagFamily::from_sak_atqa(0x20, [0x03, 0x44]), TagFamily::DESFire);
+		assert_eq!(TagFamily::from_sak_atqa(0x20, [0x00, 0x04]), TagFamily::ISODep);
+
+		let ntag = [0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x68];
+		assert_eq!(TagFamily::from_atr(&ntag), TagFamily::Ultralight);
+		let classic = [0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x6A];
+		assert_eq!(TagFamily::from_atr(&classic), TagFamily::MifareClassic);
+		assert_eq!(TagFamily::from_atr(&[0x3B, 0x80, 0x80, 0x01, 0x01]), TagFamily::ISODep);
+		assert_eq!(TagFamily::from_atr(&ICODE_SLIX_ATR), TagFamily::ISO15693);
+	}
+
+	/// The ATR a PC/SC reader builds for an ISO 15693 part 3 tag
+	const ICODE_SLIX_ATR: [u8; 20] = [0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x0B, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x71];
+
+	/// Answers PC/SC Read Binary commands like a reader with an ISO 15693 tag on it
+	struct SimulatedType5 {
+		memory: Vec<u8>,
+		reads: RefCell<Vec<(usize, usize)>>,
+	}
+	impl CardTransport for SimulatedType5 {
+		fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, pcsc::Error> {
+			if apdu[..2] != [0xFF, 0xB0] {
+				return Ok(vec![0x6A, 0x81]);
+			}
+			let block = u16::from_be_bytes([apdu[2], apdu[3]]) as usize;
+			let length = apdu[4] as usize;
+			self.reads.borrow_mut().push((block, length / 4));
+			let mut response = self.memory[block * 4..block * 4 + length].to_vec();
+			response.extend_from_slice(&[0x90, 0x00]);
+			Ok(response)
+		}
+
+		fn atr(&self) -> Option<Vec<u8>> {
+			Some(ICODE_SLIX_ATR.to_vec())
+		}
+	}
+
+	#[test]
+	fn type5_tag() {
+		let url = format!("https://live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e&padding={}", "x".repeat(100));
+		// 1 KB data area
+		let mut memory = vec![0xE1, 0x40, 0x80, 0x01];
+		memory.extend_from_slice(&NDEF::encode_message(&[NDEF::encode_uri(&url)]));
+		memory.resize(4 + 1024, 0x00);
+		let tag = SimulatedType5 { memory, reads: RefCell::new(Vec::new()) };
+		let badge = NFCBadge::new(&tag);
+		assert_eq!(badge.get_user_id().unwrap(), "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		// The capability container and then only the blocks holding the 171 byte message TLV
+		assert_eq!(*tag.reads.borrow(), [(0, 1), (1, 32), (33, 11)]);
+
+		let tag = SimulatedType5 { memory: vec![0x00; 64], reads: RefCell::new(Vec::new()) };
+		assert!(matches!(NFCBadge::new(&tag).get_user_id(), Err(Error::Message("Tag is not NDEF formatted"))));
+	}
+
+	#[test]
+	fn retry_deadline() {
+		let tag = ntag215_with_url("live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		let mut badge = NFCBadge::new(&tag);
+		badge.set_retries(10);
+		badge.set_retry_deadline(Some(Duration::from_millis(0)));
+
+		tag.timeouts.set(5);
+		let err = badge.get_tag_model().unwrap_err();
+		assert_eq!(format!("{:?}", err), "Card read timed out");
+	}
+
+	#[test]
+	fn user_id_from_query_and_path() {
+		let url = Url::parse("https://live.hack.gt/?user=cee20520-aef0-4621-af97-0b51c80c0d9c").unwrap();
+		assert_eq!(user_id_from_url(&url).unwrap(), "cee20520-aef0-4621-af97-0b51c80c0d9c");
+		let url = Url::parse("https://live.hack.gt/u/cee20520-aef0-4621-af97-0b51c80c0d9c").unwrap();
+		assert_eq!(user_id_from_url(&url).unwrap(), "cee20520-aef0-4621-af97-0b51c80c0d9c");
+		let url = Url::parse("https://live.hack.gt/u/cee20520-aef0-4621-af97-0b51c80c0d9c/").unwrap();
+		assert_eq!(user_id_from_url(&url).unwrap(), "cee20520-aef0-4621-af97-0b51c80c0d9c");
+		let url = Url::parse("https://live.hack.gt/schedule").unwrap();
+		assert!(user_id_from_url(&url).is_none());
+	}
+
+	#[test]
+	fn sdm_data() {
+		let url = Url::parse("https://live.hack.gt/?user=cee20520-aef0-4621-af97-0b51c80c0d9c&uid=04DE5F1EACC040&ctr=00003D&cmac=94EED9EE65337086").unwrap();
+		let sdm = sdm_data_from_url(&url).unwrap();
+		assert_eq!(sdm.uid, [0x04, 0xDE, 0x5F, 0x1E, 0xAC, 0xC0, 0x40]);
+		assert_eq!(sdm.counter, 0x3D);
+		assert_eq!(sdm.signature, [0x94, 0xEE, 0xD9, 0xEE, 0x65, 0x33, 0x70, 0x86]);
+		let url = Url::parse("https://live.hack.gt/?user=cee20520-aef0-4621-af97-0b51c80c0d9c&uid=04DE5F1EACC040&ctr=3D").unwrap();
+		assert!(sdm_data_from_url(&url).is_none());
+	}
+
+	#[test]
+	fn raw_user_id() {
+		let url = Url::parse("https://live.hack.gt/?ref=badge&user=cee20520%2Daef0-4621-af97-0b51c80c0d9c%25").unwrap();
+		assert_eq!(user_id_from_url(&url).unwrap(), "cee20520-aef0-4621-af97-0b51c80c0d9c%");
+		assert_eq!(user_id_from_url_raw(&url).unwrap(), "cee20520%2Daef0-4621-af97-0b51c80c0d9c%25");
+		let url = Url::parse("https://live.hack.gt/?username=someone").unwrap();
+		assert!(user_id_from_url_raw(&url).is_none());
+		let url = Url::parse("https://live.hack.gt/u/cee20520-aef0-4621-af97-0b51c80c0d9c").unwrap();
+		assert_eq!(user_id_from_url_raw(&url).unwrap(), "cee20520-aef0-4621-af97-0b51c80c0d9c");
+	}
+}
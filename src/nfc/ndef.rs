@@ -1,4 +1,6 @@
 use std::str;
+use std::io::Read;
+use std::ops::Range;
 
 #[derive(Debug, PartialEq)]
 enum ParserState {
@@ -6,46 +8,144 @@ enum ParserState {
 	NDEFInitial,
 	NDEFTypeLength,
 	NDEFPayloadLength,
+	NDEFIdLength,
 	NDEFRecordType,
+	NDEFId,
 	NDEFData
 }
 #[derive(Debug, PartialEq)]
 pub enum WellKnownType {
 	Unknown,
 	Text,
-	URI
+	URI,
+	/// An NFC Forum external type record (TNF 0x04) such as `android.com:pkg`
+	External(String),
+}
+
+/// The flags in an NDEF record header byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordFlags {
+	/// Message begin
+	pub mb: bool,
+	/// Message end
+	pub me: bool,
+	/// Chunked record
+	pub cf: bool,
+	/// Short record (one byte payload length)
+	pub sr: bool,
+	/// ID length is present
+	pub il: bool,
+	/// Type name format (e.g. 0x01 for NFC Forum well known types)
+	pub tnf: u8,
+}
+
+impl RecordFlags {
+	pub fn from_header(header: u8) -> Self {
+		Self {
+			mb: header & 1 << 7 != 0,
+			me: header & 1 << 6 != 0,
+			cf: header & 1 << 5 != 0,
+			sr: header & 1 << 4 != 0,
+			il: header & 1 << 3 != 0,
+			tnf: header & 0x07,
+		}
+	}
+}
+
+/// Composes an NDEF message one record at a time, e.g. `NDEFBuilder::new().uri(url).aar(package).build()`
+///
+/// Records are encoded with the `NDEF::encode_*` functions and joined with `NDEF::encode_message`, so short and long
+/// records and the message begin and end flags are handled for you
+#[derive(Debug, Clone, Default)]
+pub struct NDEFBuilder {
+	records: Vec<Vec<u8>>,
+}
+
+impl NDEFBuilder {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn uri(mut self, uri: &str) -> Self {
+		self.records.push(NDEF::encode_uri(uri));
+		self
+	}
+
+	pub fn text(mut self, language: &str, text: &str) -> Self {
+		self.records.push(NDEF::encode_text(language, text));
+		self
+	}
+
+	pub fn mime(mut self, mime_type: &str, data: &[u8]) -> Self {
+		self.records.push(NDEF::encode_mime(mime_type, data));
+		self
+	}
+
+	pub fn external(mut self, record_type: &str, data: &[u8]) -> Self {
+		self.records.push(NDEF::encode_external(record_type, data));
+		self
+	}
+
+	pub fn aar(mut self, package: &str) -> Self {
+		self.records.push(NDEF::encode_aar(package));
+		self
+	}
+
+	/// The NDEF message TLV followed by a terminator TLV, ready to be written to the start of a Type 2 tag's user
+	/// memory
+	pub fn build(self) -> Vec<u8> {
+		NDEF::encode_message(&self.records)
+	}
 }
 
 /// A very simple (and probably buggy) NDEF message parser based on TypeScript code I wrote for HackGT 5: https://github.com/HackGT/checkin-labels/blob/master/index.ts
 pub struct NDEF {
 	pub ndef_type: WellKnownType,
 	pub data: Vec<u8>,
+	header: u8,
 }
 
 impl NDEF {
 	pub fn parse(buffer: &[u8]) -> Result<Self, &'static str> {
+		NDEF::parse_from(buffer, 0)
+	}
+
+	/// Parses the first NDEF message found at or after `offset` in the buffer
+	///
+	/// Useful when reader framing has already been stripped or when the message TLV is known to start further in
+	pub fn parse_from(buffer: &[u8], offset: usize) -> Result<Self, &'static str> {
+		if offset > buffer.len() {
+			return Err("Offset is past the end of the buffer");
+		}
 		let mut state = ParserState::None;
 		let mut data = Vec::with_capacity(0);
 		let mut data_index: usize = 0;
+		let mut type_length: usize = 0;
+		let mut record_type = Vec::new();
+		let mut payload_length: usize = 0;
+		let mut has_id = false;
+		let mut id_length: usize = 0;
 		let mut ndef_type = WellKnownType::Unknown;
+		let mut header = 0;
 
-		let mut i: usize = 0;
+		let mut i: usize = offset;
 		while i < buffer.len() {
 			let byte = buffer[i];
 			match state {
 				ParserState::None => {
-					if byte == 0x00 {
-						// NULL block, skip
-						i += 1;
-					}
-					else if byte == 0x03 && buffer.len() > i + 2 && buffer[i + 2] == 0xD1 {
-						// NDEF message
-						// Skip length field for now
-						i += 1;
-						state = ParserState::NDEFInitial;
+					// Walk the TLV blocks to find the NDEF message so lock control TLVs and NULL blocks are skipped
+					// without having to guess from the first record's header
+					match NDEF::message_tlv_header(&buffer[i..]) {
+						Some((value_start, _)) => {
+							i += value_start;
+							state = ParserState::NDEFInitial;
+							continue;
+						},
+						None => break,
 					}
 				},
 				ParserState::NDEFInitial => {
+					header = byte;
 					if (byte & 1 << 0) != 1 {
 						return Err("Only NFC Well Known Records are supported");
 					}
@@ -58,60 +158,410 @@ impl NDEF {
 					if (byte & 1 << 7) == 0 {
 						return Err("Message must be beginning message currently");
 					}
+					has_id = (byte & 1 << 3) != 0;
 					state = ParserState::NDEFTypeLength;
 				},
 				ParserState::NDEFTypeLength => {
+					// Well known records always have a type
+					if byte == 0 {
+						return Err("Unexpected NDEF type length");
+					}
+					type_length = byte as usize;
+					record_type = Vec::with_capacity(type_length);
 					state = ParserState::NDEFPayloadLength;
 				},
 				ParserState::NDEFPayloadLength => {
-					data = Vec::with_capacity(byte as usize);
+					payload_length = byte as usize;
+					data = Vec::with_capacity(payload_length);
 					data_index = 0;
+					state = if has_id { ParserState::NDEFIdLength } else { ParserState::NDEFRecordType };
+				},
+				ParserState::NDEFIdLength => {
+					id_length = byte as usize;
 					state = ParserState::NDEFRecordType;
 				},
 				ParserState::NDEFRecordType => {
-					ndef_type = match byte {
-						0x54 => WellKnownType::Text,
-						0x55 => WellKnownType::URI,
+					record_type.push(byte);
+					if record_type.len() < type_length {
+						i += 1;
+						continue;
+					}
+					ndef_type = match record_type.as_slice() {
+						[0x54] => WellKnownType::Text,
+						[0x55] => WellKnownType::URI,
 						_ => WellKnownType::Unknown,
 					};
-					state = ParserState::NDEFData;
-				},
-				ParserState::NDEFData => {
-					// 0xFE terminates an NDEF message
-					if byte == 0xFE {
-						state = ParserState::None;
+					if id_length > 0 {
+						state = ParserState::NDEFId;
+					}
+					else if payload_length == 0 {
+						break;
 					}
 					else {
-						data.insert(data_index, byte);
-						data_index += 1;
+						state = ParserState::NDEFData;
+					}
+				},
+				ParserState::NDEFId => {
+					// The record ID isn't used
+					id_length -= 1;
+					if id_length == 0 {
+						if payload_length == 0 {
+							break;
+						}
+						state = ParserState::NDEFData;
+					}
+				},
+				ParserState::NDEFData => {
+					data.insert(data_index, byte);
+					data_index += 1;
+					// Stop at the end of the payload so any following message isn't mixed in
+					if data_index == payload_length {
+						break;
 					}
 				},
 			}
 			i += 1;
 		}
+		if state == ParserState::NDEFRecordType {
+			// Ran out of data partway through the record type
+			return Err("Unexpected NDEF type length");
+		}
 
 		Ok(Self {
 			ndef_type,
-			data
+			data,
+			header,
 		})
 	}
 
+	/// Parses the first record of the first NDEF message in a tag's NDEF data area
+	///
+	/// The data area is a run of TLV blocks with any reader or transport framing already removed, which is what every
+	/// badge read path produces no matter how the tag was read. Unlike `parse`, the message can hold more than one
+	/// record (e.g. a URI record followed by an Android Application Record).
+	pub fn parse_ndef_area(area: &[u8]) -> Result<Self, &'static str> {
+		let message = NDEF::find_message(area).ok_or("No NDEF message found")?;
+		NDEF::records(message).into_iter().next().ok_or("Invalid NDEF record")
+	}
+
+	/// Parses every NDEF message TLV in a Type 2 tag's memory
+	///
+	/// Tags can hold several NDEF messages separated by other TLVs (e.g. a URL and a separate config block). Messages
+	/// that fail to parse are skipped.
+	pub fn parse_all_messages(buffer: &[u8]) -> Vec<Self> {
+		NDEF::message_tlvs(buffer).into_iter()
+			.filter_map(|(start, _)| NDEF::parse_from(buffer, start).ok())
+			.collect()
+	}
+
+	/// Reads a tag memory dump (e.g. a fixture file) to the end and returns the records of every NDEF message in it
+	///
+	/// Malformed records end their message early like in `find_uri`. Fails if the dump holds no NDEF message.
+	pub fn parse_reader<R: Read>(mut reader: R) -> Result<Vec<Self>, super::Error> {
+		let mut buffer = Vec::new();
+		reader.read_to_end(&mut buffer)?;
+		let messages = NDEF::message_tlvs(&buffer);
+		if messages.is_empty() {
+			return Err(super::Error::Message("No NDEF message found"));
+		}
+		Ok(messages.into_iter().flat_map(|(_, value)| NDEF::records(&buffer[value])).collect())
+	}
+
+	/// Returns the content of the first URI record in any NDEF message on the tag
+	///
+	/// Unlike `parse`, this looks past records that come before the URI (e.g. the handover select records written by
+	/// some Android phones)
+	pub fn find_uri(buffer: &[u8]) -> Option<String> {
+		NDEF::find_content(buffer, WellKnownType::URI)
+	}
+
+	/// Returns the content of the first Text record in any NDEF message on the tag
+	pub fn find_text(buffer: &[u8]) -> Option<String> {
+		NDEF::find_content(buffer, WellKnownType::Text)
+	}
+
+	/// Returns the payload of the first external type record (e.g. `android.com:pkg`) in any NDEF message on the tag
+	pub fn find_external(buffer: &[u8], record_type: &str) -> Option<Vec<u8>> {
+		let ndef_type = WellKnownType::External(record_type.to_owned());
+		NDEF::message_tlvs(buffer).into_iter()
+			.flat_map(|(_, value)| NDEF::records(&buffer[value]))
+			.find(|record| record.ndef_type == ndef_type)
+			.map(|record| record.data)
+	}
+
+	fn find_content(buffer: &[u8], ndef_type: WellKnownType) -> Option<String> {
+		NDEF::message_tlvs(buffer).into_iter()
+			.flat_map(|(_, value)| NDEF::records(&buffer[value]))
+			.filter(|record| record.ndef_type == ndef_type)
+			.find_map(|record| record.get_content())
+	}
+
+	/// Splits the value of an NDEF message TLV into its records
+	///
+	/// Handles short and long records and records with an ID field. Stops at the first malformed record.
+	fn records(message: &[u8]) -> Vec<Self> {
+		let mut records = Vec::new();
+		let mut i: usize = 0;
+		while i + 2 < message.len() {
+			let header = message[i];
+			let type_length = message[i + 1] as usize;
+			i += 2;
+			let payload_length = if header & 1 << 4 != 0 {
+				i += 1;
+				message[i - 1] as usize
+			}
+			else {
+				if i + 4 > message.len() {
+					break;
+				}
+				i += 4;
+				u32::from_be_bytes([message[i - 4], message[i - 3], message[i - 2], message[i - 1]]) as usize
+			};
+			let id_length = if header & 1 << 3 != 0 {
+				match message.get(i) {
+					Some(&length) => {
+						i += 1;
+						length as usize
+					},
+					None => break,
+				}
+			}
+			else {
+				0
+			};
+			let type_end = i + type_length;
+			let payload_start = type_end + id_length;
+			if payload_start + payload_length > message.len() {
+				break;
+			}
+			let ndef_type = match (header & 0x07, &message[i..type_end]) {
+				(0x01, b"T") => WellKnownType::Text,
+				(0x01, b"U") => WellKnownType::URI,
+				(0x04, record_type) => match str::from_utf8(record_type) {
+					Ok(record_type) => WellKnownType::External(record_type.to_owned()),
+					Err(_) => WellKnownType::Unknown,
+				},
+				_ => WellKnownType::Unknown,
+			};
+			records.push(Self {
+				ndef_type,
+				data: message[payload_start..payload_start + payload_length].to_vec(),
+				header,
+			});
+			i = payload_start + payload_length;
+			// Message end flag
+			if header & 1 << 6 != 0 {
+				break;
+			}
+		}
+		records
+	}
+
+	/// Encodes a URI record, shortening the URI with the longest matching URI identifier code
+	pub fn encode_uri(uri: &str) -> Vec<u8> {
+		let (identifier, rest) = (0x01..=0x23)
+			.filter_map(|identifier| {
+				let protocol = NDEF::get_protocol(identifier)?;
+				uri.strip_prefix(protocol).map(|rest| (identifier, rest))
+			})
+			.min_by_key(|(_, rest)| rest.len())
+			.unwrap_or((0x00, uri));
+		let mut payload = vec![identifier];
+		payload.extend_from_slice(rest.as_bytes());
+		NDEF::encode_record(0x01, b"U", &payload)
+	}
+
+	/// Encodes an Android Application Record, which makes Android phones open (or offer to install) the given app
+	/// when the tag is tapped
+	///
+	/// Put it after the URI record so that other phones still see the URI first
+	pub fn encode_aar(package: &str) -> Vec<u8> {
+		NDEF::encode_external("android.com:pkg", package.as_bytes())
+	}
+
+	/// Encodes a UTF-8 Text record with the given IANA language code (e.g. `en`)
+	pub fn encode_text(language: &str, text: &str) -> Vec<u8> {
+		// Status byte: UTF-8 with the language code length in the low six bits
+		let mut payload = vec![language.len() as u8 & 0x3F];
+		payload.extend_from_slice(language.as_bytes());
+		payload.extend_from_slice(text.as_bytes());
+		NDEF::encode_record(0x01, b"T", &payload)
+	}
+
+	/// Encodes a record holding data of a MIME type (e.g. `application/json`)
+	pub fn encode_mime(mime_type: &str, data: &[u8]) -> Vec<u8> {
+		NDEF::encode_record(0x02, mime_type.as_bytes(), data)
+	}
+
+	/// Encodes an NFC Forum external type record (e.g. `hack.gt:badge`)
+	pub fn encode_external(record_type: &str, data: &[u8]) -> Vec<u8> {
+		NDEF::encode_record(0x04, record_type.as_bytes(), data)
+	}
+
+	/// Joins records from the `encode_*` functions into an NDEF message TLV (followed by a terminator TLV) that can be
+	/// written to the start of a Type 2 tag's user memory
+	pub fn encode_message(records: &[Vec<u8>]) -> Vec<u8> {
+		let mut message = Vec::new();
+		for (i, record) in records.iter().enumerate() {
+			let start = message.len();
+			message.extend_from_slice(record);
+			// Message begin and message end flags
+			if i == 0 {
+				message[start] |= 1 << 7;
+			}
+			if i == records.len() - 1 {
+				message[start] |= 1 << 6;
+			}
+		}
+		let mut tlv = vec![0x03];
+		if message.len() < 0xFF {
+			tlv.push(message.len() as u8);
+		}
+		else {
+			tlv.push(0xFF);
+			tlv.extend_from_slice(&(message.len() as u16).to_be_bytes());
+		}
+		tlv.extend(message);
+		tlv.push(0xFE);
+		tlv
+	}
+
+	/// Encodes a single record without the message begin or message end flags set
+	fn encode_record(tnf: u8, record_type: &[u8], payload: &[u8]) -> Vec<u8> {
+		let mut record = vec![tnf, record_type.len() as u8];
+		if payload.len() <= 0xFF {
+			// Short record
+			record[0] |= 1 << 4;
+			record.push(payload.len() as u8);
+		}
+		else {
+			record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+		}
+		record.extend_from_slice(record_type);
+		record.extend_from_slice(payload);
+		record
+	}
+
+	/// Finds the first NDEF message TLV in a Type 2 tag's memory and returns its value without parsing it
+	pub(crate) fn find_message(buffer: &[u8]) -> Option<&[u8]> {
+		NDEF::find_message_range(buffer).map(|range| &buffer[range])
+	}
+
+	/// Finds the position of the value of the first NDEF message TLV in a Type 2 tag's memory
+	pub(crate) fn find_message_range(buffer: &[u8]) -> Option<Range<usize>> {
+		NDEF::message_tlvs(buffer).into_iter().next().map(|(_, value)| value)
+	}
+
+	/// Reads the length of the first NDEF message TLV from the start of a Type 2 tag's memory
+	///
+	/// Only the TLV header has to be present, so the first few pages of a tag are enough to know how much more to read.
+	/// Handles the three byte `0xFF hi lo` length format.
+	pub fn tlv_length(first_pages: &[u8]) -> Option<usize> {
+		NDEF::message_tlv_header(first_pages).map(|(_, length)| length)
+	}
+
+	/// Finds the header of the first NDEF message TLV and returns where its value starts and how long it is
+	///
+	/// Unlike `message_tlvs`, the value itself doesn't have to be in the buffer
+	pub(crate) fn message_tlv_header(buffer: &[u8]) -> Option<(usize, usize)> {
+		let mut i: usize = 0;
+		while i < buffer.len() {
+			match buffer[i] {
+				0x00 => i += 1,
+				0xFE => return None,
+				tlv_type => {
+					let (length, header_length) = match *buffer.get(i + 1)? {
+						0xFF => ((*buffer.get(i + 2)? as usize) << 8 | *buffer.get(i + 3)? as usize, 4),
+						length => (length as usize, 2),
+					};
+					if tlv_type == 0x03 {
+						return Some((i + header_length, length));
+					}
+					i += header_length + length;
+				}
+			}
+		}
+		None
+	}
+
+	/// Walks the TLV blocks in a Type 2 tag's memory and returns the start of each NDEF message TLV and the position of
+	/// its value
+	fn message_tlvs(buffer: &[u8]) -> Vec<(usize, Range<usize>)> {
+		let mut messages = Vec::new();
+		let mut i: usize = 0;
+		while i < buffer.len() {
+			match buffer[i] {
+				// NULL TLV has no length field
+				0x00 => i += 1,
+				// Terminator TLV
+				0xFE => break,
+				tlv_type => {
+					let (length, header_length) = match buffer.get(i + 1) {
+						// Three byte length format
+						Some(0xFF) if i + 3 < buffer.len() => ((buffer[i + 2] as usize) << 8 | buffer[i + 3] as usize, 4),
+						Some(0xFF) | None => break,
+						Some(&length) => (length as usize, 2),
+					};
+					let start = i + header_length;
+					if start + length > buffer.len() {
+						break;
+					}
+					if tlv_type == 0x03 {
+						messages.push((i, start..start + length));
+					}
+					// Skip over lock control, memory control, and proprietary TLVs
+					i = start + length;
+				}
+			}
+		}
+		messages
+	}
+
 	fn get_uri(&self) -> Option<String> {
 		if self.data.len() < 2 || self.ndef_type != WellKnownType::URI {
 			return None;
 		}
+		// Identifiers past 0x23 are reserved so there's no way to know what the URI is supposed to start with
+		let protocol = NDEF::get_protocol(self.data[0])?;
 		let url = str::from_utf8(&self.data[1..]).ok();
-		url.map(|value| NDEF::get_protocol(self.data[0]).to_owned() + value)
+		url.map(|value| protocol.to_owned() + value)
 	}
 
 	fn get_text(&self) -> Option<String> {
-		if self.data.len() < 4 || self.ndef_type != WellKnownType::Text {
+		if self.data.is_empty() || self.ndef_type != WellKnownType::Text {
+			return None;
+		}
+		// The low six bits of the status byte hold the language code length, which can be zero
+		let language_code_length = (self.data[0] & 0x3F) as usize;
+		if self.data.len() < 1 + language_code_length {
 			return None;
 		}
-		let language_code_length = self.data[0] as usize;
 		str::from_utf8(&self.data[1 + language_code_length..]).ok().map(|value| value.to_owned())
 	}
 
+	/// The flags from the record's header
+	pub fn header_flags(&self) -> RecordFlags {
+		RecordFlags::from_header(self.header)
+	}
+
+	/// The header flags of the first record in the first NDEF message in the buffer, even if the record can't be parsed
+	///
+	/// Useful for logging why a tag was rejected (e.g. `parse` only supports short records)
+	pub fn first_record_flags(buffer: &[u8]) -> Option<RecordFlags> {
+		let (start, length) = NDEF::message_tlv_header(buffer)?;
+		if length == 0 {
+			return None;
+		}
+		buffer.get(start).map(|&header| RecordFlags::from_header(header))
+	}
+
+	/// The record's raw payload whatever its type
+	///
+	/// Use this for binary data that `get_content` rejects (e.g. legacy tags that store a blob in a Text record)
+	pub fn payload_bytes(&self) -> &[u8] {
+		&self.data
+	}
+
 	pub fn get_content(&self) -> Option<String> {
 		match self.ndef_type {
 			WellKnownType::Text => self.get_text(),
@@ -120,8 +570,8 @@ impl NDEF {
 		}
 	}
 
-	fn get_protocol(identifier: u8) -> &'static str {
-		match identifier {
+	fn get_protocol(identifier: u8) -> Option<&'static str> {
+		let protocol = match identifier {
 			0x00 => "",
 			0x01 => "http://www.",
 			0x02 => "https://www.",
@@ -158,18 +608,164 @@ impl NDEF {
 			0x21 => "urn: epc: raw:",
 			0x22 => "urn: epc:",
 			0x23 => "urn: nfc:",
-			_ => "",
-		}
+			_ => return None,
+		};
+		Some(protocol)
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::NDEF;
+	use super::{ NDEF, NDEFBuilder, WellKnownType, RecordFlags };
 	fn compare_data(data: &[u8], answer: &str) {
-		let parsed = NDEF::parse(&data).unwrap();
+		let parsed = NDEF::parse(data).unwrap();
 		assert_eq!(parsed.get_content().unwrap(), answer);
 	}
+	#[test]
+	fn parse_from_offset() {
+		let data = [0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x61, 0x62, 0x63, 0xfe, 0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x64, 0x65, 0x66, 0xfe];
+		assert_eq!(NDEF::parse_from(&data, 11).unwrap().get_content().unwrap(), "def");
+		assert!(NDEF::parse_from(&data, 23).is_err());
+	}
+	#[test]
+	fn parse_all_messages() {
+		// Two messages separated by a proprietary TLV
+		let data = [0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x61, 0x62, 0x63, 0xfd, 0x02, 0x12, 0x34, 0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x64, 0x65, 0x66, 0xfe];
+		let messages = NDEF::parse_all_messages(&data);
+		assert_eq!(messages.len(), 2);
+		assert_eq!(messages[0].get_content().unwrap(), "abc");
+		assert_eq!(messages[1].get_content().unwrap(), "def");
+		assert_eq!(NDEF::parse(&data).unwrap().get_content().unwrap(), "abc");
+	}
+	#[test]
+	fn record_with_id() {
+		// IL flag set (0xd9) with a one byte ID ("1") between the type and the payload
+		let data = [0x00, 0x03, 0x0e, 0xd9, 0x01, 0x08, 0x01, 0x55, 0x31, 0x04, 0x68, 0x61, 0x63, 0x6b, 0x2e, 0x67, 0x74, 0xfe];
+		assert_eq!(NDEF::parse(&data).unwrap().get_content().unwrap(), "https://hack.gt");
+	}
+	#[test]
+	fn unknown_uri_identifier() {
+		let data = [0x03, 0x0c, 0xd1, 0x01, 0x08, 0x55, 0x24, 0x68, 0x61, 0x63, 0x6b, 0x2e, 0x67, 0x74, 0xfe];
+		assert!(NDEF::parse(&data).unwrap().get_content().is_none());
+		let data = [0x03, 0x0c, 0xd1, 0x01, 0x08, 0x55, 0x00, 0x68, 0x61, 0x63, 0x6b, 0x2e, 0x67, 0x74, 0xfe];
+		assert_eq!(NDEF::parse(&data).unwrap().get_content().unwrap(), "hack.gt");
+	}
+	#[test]
+	fn tlv_length() {
+		assert_eq!(NDEF::tlv_length(&[0x03, 0x3C, 0xd1, 0x01]), Some(0x3C));
+		// Lock control TLV first and the three byte length format
+		assert_eq!(NDEF::tlv_length(&[0x01, 0x03, 0xa0, 0x0c, 0x34, 0x03, 0xff, 0x01, 0x20]), Some(0x120));
+		assert_eq!(NDEF::tlv_length(&[0x00, 0x00, 0x03]), None);
+		assert_eq!(NDEF::tlv_length(&[0xfe, 0x03, 0x3c]), None);
+	}
+	#[test]
+	fn text_without_language_code() {
+		compare_data(&[0x03, 0x06, 0xd1, 0x01, 0x02, 0x54, 0x00, 0x41, 0xfe], "A");
+		compare_data(&[0x03, 0x05, 0xd1, 0x01, 0x01, 0x54, 0x00, 0xfe], "");
+		// Language code longer than the payload
+		let parsed = NDEF::parse(&[0x03, 0x06, 0xd1, 0x01, 0x02, 0x54, 0x02, 0x65, 0xfe]).unwrap();
+		assert!(parsed.get_content().is_none());
+	}
+	#[test]
+	fn parse_ndef_area() {
+		let area = NDEF::encode_message(&[NDEF::encode_uri("https://live.hack.gt/u/1234"), NDEF::encode_aar("gt.hack.companion")]);
+		assert!(NDEF::parse(&area).is_err());
+		assert_eq!(NDEF::parse_ndef_area(&area).unwrap().get_content().unwrap(), "https://live.hack.gt/u/1234");
+		// Lock control TLV before the message
+		let area = [0x01, 0x03, 0xa0, 0x0c, 0x34, 0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x61, 0x62, 0x63, 0xfe];
+		assert_eq!(NDEF::parse_ndef_area(&area).unwrap().get_content().unwrap(), "abc");
+		assert_eq!(NDEF::parse_ndef_area(&[0x00, 0x00, 0xfe]).err(), Some("No NDEF message found"));
+		assert_eq!(NDEF::parse_ndef_area(&[0x03, 0x01, 0xd1, 0xfe]).err(), Some("Invalid NDEF record"));
+	}
+	#[test]
+	fn binary_text_payload() {
+		let parsed = NDEF::parse(&[0x03, 0x07, 0xd1, 0x01, 0x03, 0x54, 0x00, 0xff, 0x80, 0xfe]).unwrap();
+		assert!(parsed.get_content().is_none());
+		assert_eq!(parsed.payload_bytes(), [0x00, 0xff, 0x80]);
+	}
+	#[test]
+	fn header_flags() {
+		let data = [0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x61, 0x62, 0x63, 0xfe];
+		let flags = NDEF::parse(&data).unwrap().header_flags();
+		assert_eq!(flags, RecordFlags { mb: true, me: true, cf: false, sr: true, il: false, tnf: 0x01 });
+
+		// A long record with an ID is rejected by `parse` but its flags can still be inspected
+		let data = [0x03, 0x0c, 0xc9, 0x01, 0x00, 0x00, 0x00, 0x01, 0x01, 0x55, 0x31, 0x04, 0xfe];
+		assert_eq!(NDEF::parse(&data).err(), Some("Only short records supported currently"));
+		let flags = NDEF::first_record_flags(&data).unwrap();
+		assert!(!flags.sr && flags.il);
+		assert_eq!(NDEF::first_record_flags(&[0x00, 0xfe]), None);
+	}
+
+	#[test]
+	fn parse_reader() {
+		let dump: &[u8] = &[0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x61, 0x62, 0x63, 0xfd, 0x02, 0x12, 0x34, 0x03, 0x08, 0xd1, 0x01, 0x04, 0x54, 0x00, 0x64, 0x65, 0x66, 0xfe];
+		let records = NDEF::parse_reader(dump).unwrap();
+		assert_eq!(records.len(), 2);
+		assert_eq!(records[1].get_content().unwrap(), "def");
+		assert!(NDEF::parse_reader(&[0x00, 0xfe][..]).is_err());
+	}
+
+	#[test]
+	fn multi_byte_type() {
+		// A well known record with the two byte type "Sp" followed by a one byte payload
+		let data = [0x03, 0x06, 0xd1, 0x02, 0x01, 0x53, 0x70, 0x55, 0xfe];
+		let parsed = NDEF::parse(&data).unwrap();
+		assert_eq!(parsed.ndef_type, WellKnownType::Unknown);
+		assert_eq!(parsed.data, [0x55]);
+		// Type length runs past the end of the buffer
+		assert_eq!(NDEF::parse(&[0x03, 0x05, 0xd1, 0x08, 0x01, 0x55, 0x04]).err(), Some("Unexpected NDEF type length"));
+		assert_eq!(NDEF::parse(&[0x03, 0x04, 0xd1, 0x00, 0x01, 0x04]).err(), Some("Unexpected NDEF type length"));
+	}
+	#[test]
+	fn find_uri_after_handover_record() {
+		// A handover select record ("Hs") followed by a URI record for "https://hack.gt"
+		let data = [0x03, 0x13, 0x91, 0x02, 0x02, 0x48, 0x73, 0x12, 0xd0, 0x51, 0x01, 0x08, 0x55, 0x04, 0x68, 0x61, 0x63, 0x6b, 0x2e, 0x67, 0x74, 0xfe];
+		assert_eq!(NDEF::find_uri(&data).unwrap(), "https://hack.gt");
+		assert!(NDEF::find_text(&data).is_none());
+		assert!(NDEF::parse(&data).is_err());
+	}
+	#[test]
+	fn encode_uri_and_aar() {
+		let message = NDEF::encode_message(&[NDEF::encode_uri("https://live.hack.gt/u/1234"), NDEF::encode_aar("gt.hack.companion")]);
+		assert_eq!(&message[..8], &[0x03, 0x3b, 0x91, 0x01, 0x14, 0x55, 0x04, 0x6c]);
+		assert_eq!(*message.last().unwrap(), 0xfe);
+		assert_eq!(NDEF::find_uri(&message).unwrap(), "https://live.hack.gt/u/1234");
+		assert_eq!(NDEF::find_external(&message, "android.com:pkg").unwrap(), b"gt.hack.companion");
+		assert!(NDEF::find_external(&message, "example.com:other").is_none());
+
+		// A single record message can still be read by the original parser
+		let message = NDEF::encode_message(&[NDEF::encode_uri("http://www.hack.gt")]);
+		assert_eq!(&message[..7], &[0x03, 0x0c, 0xd1, 0x01, 0x08, 0x55, 0x01]);
+		compare_data(&message, "http://www.hack.gt");
+	}
+	#[test]
+	fn builder_round_trip() {
+		let blob = vec![0x42; 300];
+		let message = NDEFBuilder::new()
+			.uri("https://live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e")
+			.text("en", "George P. Burdell")
+			.mime("application/octet-stream", &blob)
+			.external("hack.gt:badge", &[0x01, 0x02])
+			.aar("gt.hack.companion")
+			.build();
+
+		// The 300 byte payload needs a long record which pushes the message past the one byte TLV length
+		assert_eq!(&message[..2], &[0x03, 0xff]);
+		let records = NDEF::records(NDEF::find_message(&message).unwrap());
+		assert_eq!(records.len(), 5);
+		assert!(records[0].header_flags().mb && !records[0].header_flags().me);
+		assert!(records[4].header_flags().me && !records[4].header_flags().mb);
+		assert!(!records[2].header_flags().sr && records[1].header_flags().sr);
+		assert_eq!(records[0].get_content().unwrap(), "https://live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e");
+		assert_eq!(records[1].get_content().unwrap(), "George P. Burdell");
+		assert_eq!(records[2].header_flags().tnf, 0x02);
+		assert_eq!(records[2].payload_bytes(), &blob[..]);
+		assert_eq!(NDEF::find_external(&message, "hack.gt:badge").unwrap(), [0x01, 0x02]);
+		assert_eq!(NDEF::find_external(&message, "android.com:pkg").unwrap(), b"gt.hack.companion");
+		assert_eq!(*message.last().unwrap(), 0xfe);
+	}
+
 	#[test]
 	fn parse_uri() {
 		let data = [0x1, 0x3, 0xa0, 0xc, 0x34, 0x3, 0x3b, 0xd1, 0x1, 0x37, 0x55, 0x4, 0x6c, 0x69, 0x76, 0x65, 0x2e, 0x68, 0x61, 0x63, 0x6b, 0x2e, 0x67, 0x74, 0x3f, 0x75, 0x73, 0x65, 0x72, 0x3d, 0x37, 0x64, 0x64, 0x30, 0x30, 0x30, 0x32, 0x31, 0x2d, 0x38, 0x39, 0x66, 0x64, 0x2d, 0x34, 0x39, 0x66, 0x31, 0x2d, 0x39, 0x63, 0x31, 0x37, 0x2d, 0x62, 0x64, 0x30, 0x62, 0x61, 0x37, 0x64, 0x63, 0x66, 0x39, 0x37, 0x65, 0xfe, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0];
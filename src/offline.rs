@@ -0,0 +1,213 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::SystemTime;
+use crate::api::{ CheckinAPI, CheckInReturn, Error };
+
+/// A local copy of the user IDs that are allowed in, for checking badges without a round trip to the server
+#[derive(Debug, Default, Clone)]
+pub struct AccessCache {
+	users: HashSet<String>,
+}
+
+impl AccessCache {
+	/// Downloads every user who can currently be checked in
+	///
+	/// Follows the instance's `allow_unconfirmed` setting. Blocks until every page of users has been fetched.
+	pub fn fetch(api: &CheckinAPI) -> Result<Self, Error> {
+		let mut users = HashSet::new();
+		for user in api.iter_users() {
+			let user = user?;
+			if user.accepted && (user.confirmed || api.allow_unconfirmed()) {
+				users.insert(user.id);
+			}
+		}
+		Ok(Self { users })
+	}
+
+	/// Creates a cache from a list of user IDs obtained elsewhere (e.g. a file saved before the network went down)
+	pub fn from_uuids<I: IntoIterator<Item = String>>(uuids: I) -> Self {
+		Self { users: uuids.into_iter().collect() }
+	}
+
+	pub fn contains(&self, uuid: &str) -> bool {
+		self.users.contains(uuid)
+	}
+
+	pub fn len(&self) -> usize {
+		self.users.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.users.is_empty()
+	}
+}
+
+/// How many check-ins `OfflineQueue::flush_concurrent` sends at once by default
+pub const DEFAULT_FLUSH_CONCURRENCY: usize = 8;
+
+/// What happened to the check-ins sent by `OfflineQueue::flush_concurrent`
+#[derive(Debug)]
+pub struct FlushReport {
+	/// The result for each check-in the server answered, in queue order
+	pub results: Vec<(String, Result<CheckInReturn, Error>)>,
+	/// How many check-in requests went through
+	pub succeeded: usize,
+	/// How many check-ins the server rejected
+	pub rejected: usize,
+	/// How many check-ins couldn't be sent and are still queued
+	pub requeued: usize,
+}
+
+/// A check-in waiting in an `OfflineQueue`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PendingCheckin {
+	pub uuid: String,
+	pub tag: String,
+	/// When the check-in was accepted locally
+	pub queued_at: SystemTime,
+}
+
+/// Check-ins that have been accepted locally but not sent to the server yet
+///
+/// Safe to share between reader threads
+#[derive(Debug, Default)]
+pub struct OfflineQueue {
+	pending: Mutex<Vec<PendingCheckin>>,
+}
+
+impl OfflineQueue {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Adds a check-in of a user into a tag to be sent the next time the queue is flushed
+	pub fn push(&self, uuid: &str, tag: &str) {
+		self.pending.lock().unwrap().push(PendingCheckin {
+			uuid: uuid.to_owned(),
+			tag: tag.to_owned(),
+			queued_at: SystemTime::now(),
+		});
+	}
+
+	/// The number of check-ins waiting to be sent
+	pub fn len(&self) -> usize {
+		self.pending.lock().unwrap().len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.pending.lock().unwrap().is_empty()
+	}
+
+	/// The check-ins waiting to be sent, in queue order
+	pub fn pending(&self) -> Vec<PendingCheckin> {
+		self.pending.lock().unwrap().clone()
+	}
+
+	/// Discards every queued check-in without sending it and returns what was discarded
+	///
+	/// For a queue that picked up bad data. Save the returned check-ins somewhere if they might still be needed.
+	pub fn clear(&self) -> Vec<PendingCheckin> {
+		self.pending.lock().unwrap().drain(..).collect()
+	}
+
+	/// Sends every queued check-in to the server
+	///
+	/// Returns the result for each check-in the server answered. Check-ins that failed with a network error (and any
+	/// after it, which weren't sent) stay queued in their original order for the next flush.
+	pub fn flush(&self, api: &CheckinAPI) -> Vec<(String, Result<CheckInReturn, Error>)> {
+		let mut entries = self.clear().into_iter();
+
+		let mut answered = Vec::with_capacity(entries.len());
+		let mut requeue = Vec::new();
+		for entry in entries.by_ref() {
+			match api.check_in(&entry.uuid, &entry.tag) {
+				Err(Error::Network(_)) => {
+					// The rest of the queue would most likely fail too
+					requeue.push(entry);
+					break;
+				},
+				result => answered.push((entry.uuid, result)),
+			}
+		}
+		requeue.extend(entries);
+		self.requeue(requeue);
+		answered
+	}
+
+	/// Sends every queued check-in to the server with up to `concurrency` requests in flight at once
+	///
+	/// Clears a large backlog much faster than `flush`. Check-ins for the same user are still sent one after the other
+	/// in queue order. Once a request fails with a network error no new users are started, and every check-in that
+	/// failed or wasn't sent stays queued in its original order.
+	pub fn flush_concurrent(&self, api: &CheckinAPI, concurrency: usize) -> FlushReport {
+		let entries = self.clear();
+
+		// Queue positions for each user, in the order each user first appears
+		let mut groups: Vec<Vec<usize>> = Vec::new();
+		let mut group_indices = HashMap::new();
+		for (i, entry) in entries.iter().enumerate() {
+			let group = *group_indices.entry(entry.uuid.as_str()).or_insert_with(|| {
+				groups.push(Vec::new());
+				groups.len() - 1
+			});
+			groups[group].push(i);
+		}
+
+		let results: Mutex<Vec<Option<Result<CheckInReturn, Error>>>> = Mutex::new(entries.iter().map(|_| None).collect());
+		let next_group = AtomicUsize::new(0);
+		let offline = AtomicBool::new(false);
+		thread::scope(|scope| {
+			for _ in 0..concurrency.clamp(1, groups.len().max(1)) {
+				scope.spawn(|| {
+					while !offline.load(Ordering::SeqCst) {
+						let group = match groups.get(next_group.fetch_add(1, Ordering::SeqCst)) {
+							Some(group) => group,
+							None => break,
+						};
+						for &i in group {
+							let result = api.check_in(&entries[i].uuid, &entries[i].tag);
+							let network_error = matches!(result, Err(Error::Network(_)));
+							results.lock().unwrap()[i] = Some(result);
+							if network_error {
+								// Later check-ins for this user can't go ahead of the failed one
+								offline.store(true, Ordering::SeqCst);
+								break;
+							}
+						}
+					}
+				});
+			}
+		});
+
+		let mut report = FlushReport { results: Vec::new(), succeeded: 0, rejected: 0, requeued: 0 };
+		let mut requeue = Vec::new();
+		for (entry, result) in entries.into_iter().zip(results.into_inner().unwrap()) {
+			match result {
+				Some(Err(Error::Network(_))) | None => requeue.push(entry),
+				Some(result) => {
+					if result.is_ok() {
+						report.succeeded += 1;
+					}
+					else {
+						report.rejected += 1;
+					}
+					report.results.push((entry.uuid, result));
+				},
+			}
+		}
+		report.requeued = requeue.len();
+		self.requeue(requeue);
+		report
+	}
+
+	/// Puts check-ins back at the front of the queue
+	fn requeue(&self, entries: Vec<PendingCheckin>) {
+		if !entries.is_empty() {
+			// Anything queued while the flush was running goes after the check-ins that were already waiting
+			let mut pending = self.pending.lock().unwrap();
+			pending.splice(0..0, entries);
+		}
+	}
+}
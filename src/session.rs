@@ -0,0 +1,737 @@
+use std::collections::{ HashMap, HashSet };
+use std::ffi::CStr;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+use std::thread;
+use std::time::{ Duration, Instant };
+use pcsc::{ Context, Scope };
+use url::Url;
+use crate::api::{ self, CheckinAPI, CheckInOutcome, CheckInReturn, Eligibility, UserDetails };
+use crate::nfc::{ self, CardTransport, NFCBadge, ReaderLoop, ReaderModel, TagFamily };
+use crate::offline::{ AccessCache, OfflineQueue, PendingCheckin };
+
+/// A check-in station: an authenticated API instance plus the tag that scanned badges are checked into
+pub struct CheckinSession {
+	api: CheckinAPI,
+	tag: String,
+	/// Tried in order when the server doesn't know the session's tag
+	fallback_tags: Vec<String>,
+	access_cache: Option<AccessCache>,
+	access_list: Option<AccessList>,
+	/// Maps a tag to its parent tag
+	tag_hierarchy: HashMap<String, String>,
+	/// Eligibility rules for tags that differ from the API instance's `allow_unconfirmed` setting
+	eligibility: HashMap<String, Eligibility>,
+	queue: OfflineQueue,
+	failure_handler: Option<Box<FailureHandler>>,
+	/// The host of this event's badge URLs
+	badge_host: Option<String>,
+	/// The only tag families that are read in `checkin_mode` and `identify_mode`
+	allowed_tag_families: Option<Vec<TagFamily>>,
+	/// How long repeat taps of a badge are answered with `ScanResult::Cooldown`
+	cooldown: Option<Duration>,
+	/// When each badge was last let through, for the cooldown
+	recent_scans: Mutex<HashMap<String, Instant>>,
+	/// Set by `drain` to stop accepting scans
+	draining: AtomicBool,
+	/// The number of scans currently being handled
+	in_flight: AtomicUsize,
+}
+
+/// What `CheckinSession::drain` got done before the session closed
+#[derive(Debug)]
+pub struct DrainReport {
+	/// The result of each queued check-in that was sent to the server
+	pub flushed: Vec<(String, Result<CheckInReturn, api::Error>)>,
+	/// Queued check-ins that still couldn't be sent, in queue order. Save these and send them later.
+	pub remaining: Vec<String>,
+	/// Scans that were still being handled when the timeout ran out
+	pub in_progress: usize,
+}
+
+/// Counts a scan as in flight until dropped
+struct InFlight<'a>(&'a AtomicUsize);
+impl<'a> InFlight<'a> {
+	fn start(counter: &'a AtomicUsize) -> Self {
+		counter.fetch_add(1, Ordering::SeqCst);
+		InFlight(counter)
+	}
+}
+impl Drop for InFlight<'_> {
+	fn drop(&mut self) {
+		self.0.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+type FailureHandler = dyn Fn(&str, &ScanFailure) + Send + Sync;
+
+/// Why a tap on a reader didn't end with a scanned attendee, as passed to the session's failure handler
+#[derive(Debug)]
+pub enum ScanFailure {
+	/// The tag couldn't be read or isn't a badge (e.g. an NDEF parse error or a URL without a user ID)
+	Unreadable(nfc::Error),
+	/// The badge doesn't belong to anyone. Reported by `identify_mode`; `checkin_mode` reports the server's rejection.
+	UnknownUser(String),
+	/// The request to the server failed or the server rejected the check-in
+	Server(api::Error),
+	/// The tag isn't one of the session's allowed tag families (e.g. a transit card). It wasn't read.
+	UnsupportedTag(TagFamily),
+}
+
+/// A per-station access policy that is checked before anything else when a badge is scanned
+#[derive(Debug, Clone)]
+pub enum AccessList {
+	/// Only these users are let through (e.g. a VIP area)
+	Allow(HashSet<String>),
+	/// Everyone except these users is let through
+	Deny(HashSet<String>),
+}
+
+impl AccessList {
+	/// Whether the policy lets this user through
+	pub fn permits(&self, uuid: &str) -> bool {
+		match self {
+			AccessList::Allow(uuids) => uuids.contains(uuid),
+			AccessList::Deny(uuids) => !uuids.contains(uuid),
+		}
+	}
+}
+
+/// The result of checking a user into the session's tag and each of its parent tags
+#[derive(Debug)]
+pub struct SessionCheckIn {
+	/// The tag that was checked into: the session's tag or one of its fallback tags
+	pub tag: String,
+	pub result: CheckInReturn,
+	/// The name and result of each parent tag of `tag` that was also checked into, starting with the direct parent
+	pub parents: Vec<(String, Result<CheckInReturn, api::Error>)>,
+}
+
+/// What happened to a badge scanned with `CheckinSession::scan`
+#[derive(Debug)]
+pub enum ScanResult {
+	/// Checked in with the server
+	CheckedIn(Box<SessionCheckIn>),
+	/// Allowed in by the access cache. The check-in is sent to the server by `CheckinSession::sync`.
+	Queued,
+	/// Not in the access cache
+	Rejected,
+	/// Not permitted by the session's `AccessList`. The server isn't contacted.
+	AccessDenied,
+	/// The badge URL is for a different event than the session's badge host. The server isn't contacted.
+	WrongEvent,
+	/// The badge was let through moments ago and is still in the session's scan cooldown. The server isn't contacted.
+	Cooldown,
+}
+
+/// The outcome of a single self-test check
+#[derive(Debug)]
+pub struct SelfTestCheck {
+	pub passed: bool,
+	/// A human-readable explanation that can be shown to volunteers
+	pub message: String,
+}
+impl SelfTestCheck {
+	fn pass(message: &str) -> Self {
+		Self { passed: true, message: message.to_owned() }
+	}
+	fn fail(message: String) -> Self {
+		Self { passed: false, message }
+	}
+}
+
+/// Results of `CheckinSession::self_test`
+#[derive(Debug)]
+pub struct SelfTestReport {
+	pub pcsc_service: SelfTestCheck,
+	pub reader_connected: SelfTestCheck,
+	pub server_reachable: SelfTestCheck,
+	pub auth_valid: SelfTestCheck,
+	pub tag_exists: SelfTestCheck,
+}
+impl SelfTestReport {
+	/// Whether every check passed
+	pub fn passed(&self) -> bool {
+		self.checks().iter().all(|check| check.passed)
+	}
+
+	/// All of the checks in the order they were run
+	pub fn checks(&self) -> [&SelfTestCheck; 5] {
+		[&self.pcsc_service, &self.reader_connected, &self.server_reachable, &self.auth_valid, &self.tag_exists]
+	}
+}
+
+impl CheckinSession {
+	pub fn new(api: CheckinAPI, tag: &str) -> Self {
+		Self {
+			api,
+			tag: tag.to_owned(),
+			fallback_tags: Vec::new(),
+			access_cache: None,
+			access_list: None,
+			tag_hierarchy: HashMap::new(),
+			eligibility: HashMap::new(),
+			queue: OfflineQueue::new(),
+			failure_handler: None,
+			badge_host: None,
+			allowed_tag_families: None,
+			cooldown: None,
+			recent_scans: Mutex::new(HashMap::new()),
+			draining: AtomicBool::new(false),
+			in_flight: AtomicUsize::new(0),
+		}
+	}
+
+	/// Sets the host this event's badge URLs point to (e.g. `live.hack.gt`)
+	///
+	/// Badges and QR codes from other events that share badge stock point somewhere else, so `scan_url` and
+	/// `checkin_mode` answer them with `ScanResult::WrongEvent` instead of a confusing unknown user error
+	pub fn with_badge_host(mut self, host: &str) -> Self {
+		self.badge_host = Some(host.to_owned());
+		self
+	}
+
+	/// Whether a badge URL belongs to this session's event. Always true if no badge host is set.
+	pub fn is_current_event(&self, url: &Url) -> bool {
+		match &self.badge_host {
+			Some(badge_host) => url.host_str().is_some_and(|host| host.eq_ignore_ascii_case(badge_host)),
+			None => true,
+		}
+	}
+
+	/// Sets a function that is called with the reader name whenever a tap fails in `checkin_mode` or `identify_mode`
+	///
+	/// Use it to feed dashboards (e.g. to spot a reader with lots of unreadable taps). Without a handler, failures are
+	/// logged to stderr.
+	pub fn with_failure_handler<F>(mut self, handler: F) -> Self
+		where F: Fn(&str, &ScanFailure),
+			  F: Send + Sync + 'static,
+	{
+		self.failure_handler = Some(Box::new(handler));
+		self
+	}
+
+	/// Answers repeat taps of a badge that was let through less than `cooldown` ago with `ScanResult::Cooldown`
+	///
+	/// Stops accidental double check-ins, including a badge tapped on two readers at once. In `checkin_mode` the
+	/// reader flashes its red LED without beeping for these taps so volunteers don't get positive feedback for them.
+	/// An ACR1252U can't time a flash, so its red LED stays on until the next badge is let through.
+	pub fn with_scan_cooldown(mut self, cooldown: Duration) -> Self {
+		self.cooldown = Some(cooldown);
+		self
+	}
+
+	/// Starts a cooldown for the badge unless it's still in one
+	///
+	/// Returns whether the badge may be scanned. Checking and starting happen under one lock so a badge tapped on two
+	/// readers at once is only let through once.
+	fn reserve_cooldown(&self, uuid: &str) -> bool {
+		let cooldown = match self.cooldown {
+			Some(cooldown) => cooldown,
+			None => return true,
+		};
+		let now = Instant::now();
+		let mut recent_scans = self.recent_scans.lock().unwrap();
+		recent_scans.retain(|_, scanned_at| now.duration_since(*scanned_at) < cooldown);
+		if recent_scans.contains_key(uuid) {
+			return false;
+		}
+		recent_scans.insert(uuid.to_owned(), now);
+		true
+	}
+
+	/// Ends the cooldown started by `reserve_cooldown` for a scan that didn't let the badge through
+	fn release_cooldown(&self, uuid: &str) {
+		if self.cooldown.is_some() {
+			self.recent_scans.lock().unwrap().remove(uuid);
+		}
+	}
+
+	/// Only reads tags of these families in `checkin_mode` and `identify_mode`, e.g. `TagFamily::Ultralight` for NTAG
+	/// badges
+	///
+	/// Other tags (transit cards, bank cards) are reported to the failure handler as `ScanFailure::UnsupportedTag`
+	/// straight away instead of failing partway through a read. The family comes from the SAK and ATQA on readers
+	/// built around a PN532 (see `NFCBadge::get_tag_info`).
+	pub fn with_allowed_tag_families(mut self, families: Vec<TagFamily>) -> Self {
+		self.allowed_tag_families = Some(families);
+		self
+	}
+
+	fn check_tag_family<T: CardTransport + ?Sized>(&self, badge: &NFCBadge<T>) -> Result<(), ScanFailure> {
+		let families = match &self.allowed_tag_families {
+			Some(families) => families,
+			None => return Ok(()),
+		};
+		let family = badge.get_tag_info().map_err(ScanFailure::Unreadable)?.family;
+		if families.contains(&family) {
+			Ok(())
+		}
+		else {
+			Err(ScanFailure::UnsupportedTag(family))
+		}
+	}
+
+	fn report_failure(&self, reader_name: &CStr, failure: ScanFailure) {
+		let reader_name = reader_name.to_string_lossy();
+		match &self.failure_handler {
+			Some(handler) => handler(&reader_name, &failure),
+			None => eprintln!("Scan failed on {}: {:?}", reader_name, failure),
+		}
+	}
+
+	/// Sets up parent tags that are checked into along with their child tags
+	///
+	/// `hierarchy` maps a tag to its parent (e.g. `"day2-lunch"` to `"day2"`). Parents can have parents of their own.
+	/// The server has no notion of tag hierarchy so each parent check-in is a separate request made after the
+	/// session's tag was checked into.
+	pub fn with_tag_hierarchy(mut self, hierarchy: HashMap<String, String>) -> Self {
+		self.tag_hierarchy = hierarchy;
+		self
+	}
+
+	/// Sets who may be checked into each tag, e.g. `"registration"` letting in accepted users who haven't confirmed
+	/// yet while `"lunch"` requires both
+	///
+	/// Tags with a rule are checked against it before the check-in is sent to the server (see
+	/// `CheckinAPI::check_in_outcome_with`). Other tags follow the API instance's `allow_unconfirmed` setting.
+	pub fn with_eligibility_policy(mut self, policy: HashMap<String, Eligibility>) -> Self {
+		self.eligibility = policy;
+		self
+	}
+
+	/// Sets tags to try in order when the server reports the session's tag as unknown (e.g. `"lunch-sat"` for a
+	/// session on `"lunch"`)
+	///
+	/// Handles tag names that drifted between registration systems. A tag whose eligibility rule turns the user away
+	/// also moves on to the next tag. `check_in` reports which tag was used.
+	pub fn with_fallback_tags(mut self, tags: Vec<String>) -> Self {
+		self.fallback_tags = tags;
+		self
+	}
+
+	pub fn fallback_tags(&self) -> &[String] {
+		&self.fallback_tags
+	}
+
+	/// The parents of the session's tag, starting with the direct parent
+	pub fn parent_tags(&self) -> Vec<&str> {
+		self.parents_of(&self.tag)
+	}
+
+	fn parents_of<'a>(&'a self, start: &'a str) -> Vec<&'a str> {
+		let mut parents: Vec<&str> = Vec::new();
+		let mut tag = start;
+		while let Some(parent) = self.tag_hierarchy.get(tag) {
+			// Stop if the hierarchy loops back on itself
+			if parent == start || parents.contains(&parent.as_str()) {
+				break;
+			}
+			parents.push(parent);
+			tag = parent;
+		}
+		parents
+	}
+
+	/// Checks a user into the session's tag and then into each of its parent tags
+	///
+	/// If the server doesn't know the session's tag, or the tag's eligibility rule turns the user away, the fallback
+	/// tags are tried in order and the first one that lets the user in is used instead, along with its own parent
+	/// tags. Parent tags are only checked into if the check-in request for the matched tag succeeded.
+	pub fn check_in(&self, uuid: &str) -> Result<SessionCheckIn, crate::Error> {
+		let mut tag = &self.tag;
+		for fallback in &self.fallback_tags {
+			match self.check_in_outcome(uuid, tag)? {
+				CheckInOutcome::UnknownTag => {},
+				CheckInOutcome::NotAccepted if self.eligibility.contains_key(tag) => {},
+				outcome => return self.finish_check_in(uuid, tag, outcome),
+			}
+			tag = fallback;
+		}
+		let outcome = self.check_in_outcome(uuid, tag)?;
+		self.finish_check_in(uuid, tag, outcome)
+	}
+
+	/// Checks into the parents of the tag that was matched by `check_in`
+	fn finish_check_in(&self, uuid: &str, tag: &str, outcome: CheckInOutcome) -> Result<SessionCheckIn, crate::Error> {
+		let result = outcome.into_result()?;
+		let parents = self.parents_of(tag).into_iter()
+			.map(|parent| (parent.to_owned(), self.check_in_outcome(uuid, parent).and_then(CheckInOutcome::into_result)))
+			.collect();
+		Ok(SessionCheckIn { tag: tag.to_owned(), result, parents })
+	}
+
+	/// Checks a user into a single tag, following the tag's eligibility rule if it has one
+	fn check_in_outcome(&self, uuid: &str, tag: &str) -> Result<CheckInOutcome, api::Error> {
+		match self.eligibility.get(tag) {
+			Some(&eligibility) => self.api.check_in_outcome_with(uuid, tag, eligibility),
+			None => self.api.check_in_outcome(uuid, tag),
+		}
+	}
+
+	pub fn api(&self) -> &CheckinAPI {
+		&self.api
+	}
+
+	/// The tag that badges are checked into
+	pub fn tag(&self) -> &str {
+		&self.tag
+	}
+
+	/// Switches the session to checking badges against a local cache instead of the server
+	///
+	/// With a cache set, `scan` accepts or rejects badges instantly and queues the actual check-ins until `sync` is
+	/// called, so the station keeps working through network drops. Pass `None` to go back to checking in online.
+	pub fn set_access_cache(&mut self, access_cache: Option<AccessCache>) {
+		self.access_cache = access_cache;
+	}
+
+	pub fn access_cache(&self) -> Option<&AccessCache> {
+		self.access_cache.as_ref()
+	}
+
+	/// Restricts which users this station lets through, on top of the normal check-in rules
+	///
+	/// Badges the list doesn't permit are answered with `ScanResult::AccessDenied` by `scan` without contacting the
+	/// server or touching the access cache. Pass `None` to remove the restriction.
+	pub fn set_access_list(&mut self, access_list: Option<AccessList>) {
+		self.access_list = access_list;
+	}
+
+	pub fn access_list(&self) -> Option<&AccessList> {
+		self.access_list.as_ref()
+	}
+
+	/// Check-ins accepted by the access cache that haven't been sent to the server yet
+	pub fn queue(&self) -> &OfflineQueue {
+		&self.queue
+	}
+
+	/// Handles a scanned user ID, either locally with the access cache or by checking in with the server
+	///
+	/// Fails once `drain` has been called
+	pub fn scan(&self, uuid: &str) -> Result<ScanResult, crate::Error> {
+		let _in_flight = InFlight::start(&self.in_flight);
+		if self.draining.load(Ordering::SeqCst) {
+			return Err(api::Error::Message("Check-in session is closed").into());
+		}
+		if self.access_list.as_ref().is_some_and(|list| !list.permits(uuid)) {
+			return Ok(ScanResult::AccessDenied);
+		}
+		if !self.reserve_cooldown(uuid) {
+			return Ok(ScanResult::Cooldown);
+		}
+		let result = match &self.access_cache {
+			Some(cache) if cache.contains(uuid) => {
+				self.queue.push(uuid, &self.tag);
+				Ok(ScanResult::Queued)
+			},
+			Some(_) => Ok(ScanResult::Rejected),
+			None => self.check_in(uuid).map(|check_in| ScanResult::CheckedIn(Box::new(check_in))),
+		};
+		if !matches!(result, Ok(ScanResult::Queued) | Ok(ScanResult::CheckedIn(_))) {
+			self.release_cooldown(uuid);
+		}
+		result
+	}
+
+	/// Handles a badge URL (read from a badge or scanned from a QR code) like `scan`, after checking that it's for
+	/// this session's event
+	pub fn scan_url(&self, url: &Url) -> Result<ScanResult, crate::Error> {
+		if !self.is_current_event(url) {
+			return Ok(ScanResult::WrongEvent);
+		}
+		let uuid = nfc::user_id_from_url(url).ok_or(nfc::Error::Message("URL did not contain user ID"))?;
+		self.scan(&uuid)
+	}
+
+	/// Closes the session at the end of a shift without losing any check-ins
+	///
+	/// Stops accepting new scans, waits up to `timeout` for scans that are already being handled to finish, and then
+	/// sends the offline queue. Takes `&self` so it can be called while reader threads still hold the session (e.g.
+	/// through an `Arc`). Stop the reader loop afterwards.
+	pub fn drain(&self, timeout: Duration) -> DrainReport {
+		self.draining.store(true, Ordering::SeqCst);
+		let deadline = Instant::now() + timeout;
+		while self.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+			thread::sleep(Duration::from_millis(10));
+		}
+		let in_progress = self.in_flight.load(Ordering::SeqCst);
+		let flushed = self.sync();
+		DrainReport {
+			flushed,
+			remaining: self.queue.pending().into_iter().map(|entry| entry.uuid).collect(),
+			in_progress,
+		}
+	}
+
+	/// Closes the session and hands back the check-ins that are still queued
+	///
+	/// Dropping a session discards its queue, so call this after `drain` and save what it returns. A session shared
+	/// with a reader loop can be taken back with `Arc::try_unwrap` once the loop has stopped.
+	pub fn shutdown(self) -> Vec<PendingCheckin> {
+		self.queue.clear()
+	}
+
+	/// Sends queued check-ins to the server
+	///
+	/// See `OfflineQueue::flush` for what is returned and what stays queued. Queued check-ins are only sent for the
+	/// session's own tag, not its parent tags.
+	pub fn sync(&self) -> Vec<(String, Result<CheckInReturn, api::Error>)> {
+		self.queue.flush(&self.api)
+	}
+
+	/// Runs the station: every tapped badge is read and passed to `scan`, and the result is passed to `on_scan`
+	///
+	/// Taps that fail are passed to the failure handler instead. Keep a clone of the `Arc` to `sync` the offline queue
+	/// or `drain` the session while the station runs.
+	pub fn checkin_mode<F>(self: Arc<Self>, on_scan: F) -> ReaderLoop
+		where F: Fn(ScanResult),
+			  F: Send + 'static,
+	{
+		nfc::handle_cards(move |card, reader_name, _reader_index| {
+			let badge = NFCBadge::new(card);
+			if let Err(failure) = self.check_tag_family(&badge) {
+				return self.report_failure(reader_name, failure);
+			}
+			let url = match badge.get_badge_url() {
+				Ok(url) => url,
+				Err(err) => return self.report_failure(reader_name, ScanFailure::Unreadable(err)),
+			};
+			if !self.is_current_event(&url) {
+				return on_scan(ScanResult::WrongEvent);
+			}
+			let uuid = match nfc::user_id_from_url(&url) {
+				Some(uuid) => uuid,
+				None => {
+					let err = nfc::Error::Message("URL did not contain user ID");
+					return self.report_failure(reader_name, ScanFailure::Unreadable(err));
+				},
+			};
+			match self.scan(&uuid) {
+				Ok(ScanResult::Cooldown) => {
+					// Errors only mean the reader can't control its LEDs
+					let _ = match badge.reader_model() {
+						ReaderModel::ACR122U => badge.flash_led(true, false, 5),
+						// Can't time a flash, so the LED stays red until the next badge is let through
+						ReaderModel::ACR1252U => badge.set_led(true, false),
+					};
+					on_scan(ScanResult::Cooldown);
+				},
+				Ok(result) => {
+					if badge.reader_model() == ReaderModel::ACR1252U {
+						let _ = badge.set_led(false, false);
+					}
+					on_scan(result);
+				},
+				Err(crate::Error::Api(err)) => self.report_failure(reader_name, ScanFailure::Server(err)),
+				Err(crate::Error::Nfc(err)) => self.report_failure(reader_name, ScanFailure::Unreadable(err)),
+			}
+		}, |_, _| {})
+	}
+
+	/// Runs the station as a read-only info kiosk
+	///
+	/// Every tapped badge is looked up and the attendee's information is passed to `display` (`None` for a badge that
+	/// doesn't belong to anyone) without checking them in. Taps that fail are passed to the failure handler.
+	pub fn identify_mode<F>(self: Arc<Self>, display: F) -> ReaderLoop
+		where F: Fn(Option<UserDetails>),
+			  F: Send + 'static,
+	{
+		nfc::handle_cards(move |card, reader_name, _reader_index| {
+			let badge = NFCBadge::new(card);
+			if let Err(failure) = self.check_tag_family(&badge) {
+				return self.report_failure(reader_name, failure);
+			}
+			let uuid = match badge.get_user_id() {
+				Ok(uuid) => uuid,
+				Err(err) => return self.report_failure(reader_name, ScanFailure::Unreadable(err)),
+			};
+			match self.api.get_user(&uuid) {
+				Ok(Some(user)) => display(Some(user)),
+				Ok(None) => {
+					self.report_failure(reader_name, ScanFailure::UnknownUser(uuid));
+					display(None);
+				},
+				Err(err) => self.report_failure(reader_name, ScanFailure::Server(err)),
+			}
+		}, |_, _| {})
+	}
+
+	/// Runs every health check needed before opening the line and reports on each of them
+	///
+	/// Checks that the PC/SC service is running, a reader is plugged in, the check-in server can be reached, the auth
+	/// token is still valid, and the configured tag exists
+	pub fn self_test(&self) -> SelfTestReport {
+		let (pcsc_service, reader_connected) = match Context::establish(Scope::User) {
+			Ok(ctx) => {
+				let reader_connected = match ctx.list_readers_owned() {
+					Ok(readers) => {
+						// Ignore the pseudo reader created by Windows Hello
+						let count = readers.iter()
+							.filter(|name| !name.to_string_lossy().contains("Windows Hello"))
+							.count();
+						if count > 0 {
+							SelfTestCheck::pass("NFC reader connected")
+						}
+						else {
+							SelfTestCheck::fail("No NFC reader connected".to_owned())
+						}
+					},
+					Err(pcsc::Error::NoReadersAvailable) => SelfTestCheck::fail("No NFC reader connected".to_owned()),
+					Err(err) => SelfTestCheck::fail(format!("Could not list NFC readers: {}", err)),
+				};
+				(SelfTestCheck::pass("Smart card service running"), reader_connected)
+			},
+			Err(err) => (
+				SelfTestCheck::fail(format!("Smart card service not running: {}", err)),
+				SelfTestCheck::fail("Could not check for NFC readers".to_owned()),
+			),
+		};
+
+		let (server_reachable, auth_valid, tag_exists) = match self.api.tag_exists(&self.tag) {
+			Ok(exists) => {
+				let tag_exists = if exists {
+					SelfTestCheck::pass("Tag exists")
+				}
+				else {
+					SelfTestCheck::fail(format!("Tag '{}' does not exist", self.tag))
+				};
+				(SelfTestCheck::pass("Check-in server reachable"), SelfTestCheck::pass("Logged in"), tag_exists)
+			},
+			Err(api::Error::Network(err)) if err.is_connect() || err.is_timeout() || err.is_request() => (
+				SelfTestCheck::fail(format!("Could not reach check-in server: {}", err)),
+				SelfTestCheck::fail("Could not check login".to_owned()),
+				SelfTestCheck::fail("Could not check tag".to_owned()),
+			),
+			Err(err) => (
+				SelfTestCheck::pass("Check-in server reachable"),
+				SelfTestCheck::fail(format!("Login is invalid or expired: {:?}", err)),
+				SelfTestCheck::fail("Could not check tag".to_owned()),
+			),
+		};
+
+		SelfTestReport {
+			pcsc_service,
+			reader_connected,
+			server_reachable,
+			auth_valid,
+			tag_exists,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ AccessList, CheckinSession, ScanResult, ScanFailure };
+	use crate::api::CheckinAPI;
+	use crate::nfc::{ NFCBadge, TagFamily, TraceReplay, ApduExchange };
+	use crate::offline::AccessCache;
+	use url::Url;
+	use std::time::Duration;
+
+	#[test]
+	fn allowed_tag_families() {
+		let exchange = |request: &[u8], response: &[u8]| ApduExchange {
+			request: request.to_vec(),
+			response: response.to_vec(),
+			status: [0x90, 0x00],
+		};
+		// A MIFARE Classic transit card (SAK 0x08)
+		let replay = TraceReplay::new(vec![
+			exchange(&[0xFF, 0xCA, 0x00, 0x00, 0x00], &[0x04, 0x8A, 0x3C, 0x12]),
+			exchange(&[0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x4A, 0x01, 0x00], &[0xD5, 0x4B, 0x01, 0x01, 0x00, 0x04, 0x08, 0x04, 0x04, 0x8A, 0x3C, 0x12]),
+		]);
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let session = CheckinSession::new(api, "lunch").with_allowed_tag_families(vec![TagFamily::Ultralight]);
+		let result = session.check_tag_family(&NFCBadge::new(&replay));
+		assert!(matches!(result, Err(ScanFailure::UnsupportedTag(TagFamily::MifareClassic))));
+		assert!(replay.finished());
+	}
+
+	#[test]
+	fn scan_cooldown() {
+		const USER: &str = "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let mut session = CheckinSession::new(api, "lunch").with_scan_cooldown(Duration::from_millis(200));
+		session.set_access_cache(Some(AccessCache::from_uuids(vec![USER.to_owned()])));
+
+		assert!(matches!(session.scan(USER), Ok(ScanResult::Queued)));
+		assert!(matches!(session.scan(USER), Ok(ScanResult::Cooldown)));
+		// Rejected badges don't start a cooldown
+		assert!(matches!(session.scan("cee20520-aef0-4621-af97-0b51c80c0d9c"), Ok(ScanResult::Rejected)));
+		assert!(matches!(session.scan("cee20520-aef0-4621-af97-0b51c80c0d9c"), Ok(ScanResult::Rejected)));
+		std::thread::sleep(Duration::from_millis(250));
+		assert!(matches!(session.scan(USER), Ok(ScanResult::Queued)));
+		assert_eq!(session.queue().len(), 2);
+
+		// Neither do scans that fail
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let session = CheckinSession::new(api, "lunch").with_scan_cooldown(Duration::from_secs(60));
+		assert!(session.scan(USER).is_err());
+		assert!(session.scan(USER).is_err());
+	}
+
+	#[test]
+	fn access_list_is_checked_first() {
+		const VIP: &str = "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+		const GUEST: &str = "cee20520-aef0-4621-af97-0b51c80c0d9c";
+		// Nothing is listening here so any request to the server would fail
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let mut session = CheckinSession::new(api, "vip-lounge");
+		session.set_access_cache(Some(AccessCache::from_uuids(vec![VIP.to_owned(), GUEST.to_owned()])));
+
+		session.set_access_list(Some(AccessList::Allow(vec![VIP.to_owned()].into_iter().collect())));
+		assert!(matches!(session.scan(GUEST), Ok(ScanResult::AccessDenied)));
+		assert!(matches!(session.scan(VIP), Ok(ScanResult::Queued)));
+
+		session.set_access_list(Some(AccessList::Deny(vec![VIP.to_owned()].into_iter().collect())));
+		assert!(matches!(session.scan(VIP), Ok(ScanResult::AccessDenied)));
+		assert!(matches!(session.scan(GUEST), Ok(ScanResult::Queued)));
+		assert_eq!(session.queue().len(), 2);
+	}
+
+	#[test]
+	fn drain() {
+		const USER: &str = "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let mut session = CheckinSession::new(api, "lunch");
+		session.set_access_cache(Some(AccessCache::from_uuids(vec![USER.to_owned()])));
+		assert!(matches!(session.scan(USER), Ok(ScanResult::Queued)));
+
+		// The server can't be reached so the queued check-in is handed back
+		let report = session.drain(Duration::from_secs(1));
+		assert!(report.flushed.is_empty());
+		assert_eq!(report.remaining, [USER]);
+		assert_eq!(report.in_progress, 0);
+		assert!(session.scan(USER).is_err());
+
+		let leftovers = session.shutdown();
+		assert_eq!(leftovers.len(), 1);
+		assert_eq!(leftovers[0].uuid, USER);
+	}
+
+	#[test]
+	fn wrong_event() {
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let mut session = CheckinSession::new(api, "lunch").with_badge_host("live.hack.gt");
+		session.set_access_cache(Some(AccessCache::from_uuids(vec!["7dd00021-89fd-49f1-9c17-bd0ba7dcf97e".to_owned()])));
+
+		let url = Url::parse("https://live.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e").unwrap();
+		assert!(matches!(session.scan_url(&url), Ok(ScanResult::Queued)));
+		let url = Url::parse("https://live.horizons.hack.gt/?user=7dd00021-89fd-49f1-9c17-bd0ba7dcf97e").unwrap();
+		assert!(matches!(session.scan_url(&url), Ok(ScanResult::WrongEvent)));
+		let url = Url::parse("https://LIVE.hack.gt/schedule").unwrap();
+		assert!(session.scan_url(&url).is_err());
+	}
+
+	#[test]
+	fn parent_tags() {
+		let api = CheckinAPI::from_token(String::new(), "http://127.0.0.1:9");
+		let hierarchy = [("day2-lunch", "day2"), ("day2", "hackathon"), ("hackathon", "day2-lunch")].iter()
+			.map(|&(child, parent)| (child.to_owned(), parent.to_owned()))
+			.collect();
+		let session = CheckinSession::new(api, "day2-lunch").with_tag_hierarchy(hierarchy);
+		assert_eq!(session.parent_tags(), ["day2", "hackathon"]);
+	}
+}
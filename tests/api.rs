@@ -0,0 +1,765 @@
+//! Runs the check-in API against a mock HTTP server so the full request path (cookies, JSON decoding, error mapping)
+//! is exercised without a real check-in instance
+
+use hackgt_nfc::api::{ AutoCheckinAPI, CheckInOutcome, CheckinAPI, Error };
+use httpmock::prelude::*;
+use std::sync::Arc;
+use std::thread;
+
+const AUTH_TOKEN: &str = "6b1e8a3f0c2d4e5f6a7b8c9d0e1f2a3b4c5d6e7f8a9b0c1d2e3f4a5b6c7d8e9f";
+const USER_ID: &str = "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e";
+
+fn user_data() -> &'static str {
+	r#"{
+		"id": "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e",
+		"applied": true,
+		"accepted": true,
+		"confirmed": true,
+		"confirmationBranch": "Participant",
+		"application": { "type": "Participant" },
+		"confirmation": { "type": "Participant" },
+		"name": "George P. Burdell",
+		"email": "george@gatech.edu",
+		"questions": []
+	}"#
+}
+
+fn tag_data(name: &str, success: bool) -> String {
+	format!(r#"{{
+		"tag": {{ "name": "{}" }},
+		"checked_in": true,
+		"checkin_success": {},
+		"last_successful_checkin": {{ "checked_in_date": "2020-02-15T18:00:00Z", "checked_in_by": "kiosk" }}
+	}}"#, name, success)
+}
+
+#[test]
+fn login_extracts_auth_cookie() {
+	let server = MockServer::start();
+	let login = server.mock(|when, then| {
+		when.method(POST)
+			.path("/api/user/login")
+			.body_contains("username=kiosk")
+			.body_contains("password=hunter2");
+		then.status(200)
+			.header("Set-Cookie", format!("auth={}; Path=/; HttpOnly", AUTH_TOKEN));
+	});
+
+	let instance = CheckinAPI::login("kiosk", "hunter2", &server.base_url()).unwrap();
+	login.assert();
+	assert_eq!(instance.auth_token(), AUTH_TOKEN);
+}
+
+#[test]
+fn login_json_token() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/api/user/login");
+		then.status(200)
+			.header("Content-Type", "application/json; charset=utf-8")
+			.body(format!(r#"{{ "token": "{}" }}"#, AUTH_TOKEN));
+	});
+
+	// The standard server always sets a cookie so the JSON body is ignored unless asked for
+	let result = CheckinAPI::login("kiosk", "hunter2", &server.base_url());
+	assert!(matches!(result, Err(Error::Message("No auth token set by server"))));
+
+	let mut instance = CheckinAPI::from_token(String::new(), &server.base_url()).with_json_token_login(true);
+	instance.relogin("kiosk", "hunter2").unwrap();
+	assert_eq!(instance.auth_token(), AUTH_TOKEN);
+}
+
+#[test]
+fn login_in_thread() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/api/user/login");
+		then.status(200)
+			.header("Set-Cookie", format!("auth={}; Path=/; HttpOnly", AUTH_TOKEN));
+	});
+
+	let handle = CheckinAPI::login_in_thread("kiosk", "hunter2", &server.base_url());
+	assert_eq!(handle.join().unwrap().unwrap().auth_token(), AUTH_TOKEN);
+}
+
+#[test]
+fn login_rejected() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/api/user/login");
+		then.status(401);
+	});
+
+	let result = CheckinAPI::login("kiosk", "wrong", &server.base_url());
+	assert!(matches!(result, Err(Error::Message("Invalid username or password"))));
+}
+
+#[test]
+fn check_in_success() {
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST)
+			.path("/graphql")
+			.header("Cookie", format!("auth={}", AUTH_TOKEN))
+			.body_contains(r#""operationName":"CheckInTag""#)
+			.body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}, {}] }} }} }}"#,
+				user_data(), tag_data("lunch", false), tag_data("123", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let (success, user, tag) = instance.check_in(USER_ID, "123").unwrap();
+	check_in.assert();
+	assert!(success);
+	assert_eq!(user.name, "George P. Burdell");
+	assert_eq!(tag.tag.name, "123");
+}
+
+#[test]
+fn check_in_graphql_error() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": null, "errors": [{ "message": "Not authorized" }] }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	match instance.check_in(USER_ID, "123") {
+		Err(Error::GraphQL(errors)) => assert_eq!(errors[0].message, "Not authorized"),
+		other => panic!("Expected a GraphQL error, got {:?}", other.map(|(success, _, _)| success)),
+	}
+}
+
+#[test]
+fn raw_graphql() {
+	let server = MockServer::start();
+	let query = server.mock(|when, then| {
+		when.method(POST)
+			.path("/graphql")
+			.header("Cookie", format!("auth={}", AUTH_TOKEN))
+			.body_contains(r#""query":"query { tags { name } }""#)
+			.body_contains(r#""variables":{"limit":1}"#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "lunch" }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let data: serde_json::Value = instance.raw_graphql("query { tags { name } }", serde_json::json!({ "limit": 1 })).unwrap();
+	assert_eq!(data["tags"][0]["name"], "lunch");
+	query.assert();
+}
+
+#[test]
+fn get_tags_names() {
+	let server = MockServer::start();
+	let tags = server.mock(|when, then| {
+		when.method(POST)
+			.path("/graphql")
+			.body_contains(r#""operationName":"TagsGet""#)
+			.body_contains(r#""only_current":true"#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "123" }, { "name": "lunch" }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	assert_eq!(instance.get_tags_names(true).unwrap(), vec!["123", "lunch"]);
+	tags.assert();
+}
+
+#[test]
+fn custom_endpoint_paths() {
+	let server = MockServer::start();
+	let login = server.mock(|when, then| {
+		when.method(POST).path("/auth/login");
+		then.status(200)
+			.header("Set-Cookie", format!("auth={}; Path=/; HttpOnly", AUTH_TOKEN));
+	});
+	let tags = server.mock(|when, then| {
+		when.method(POST).path("/api/graphql").body_contains(r#""operationName":"TagsGet""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "123" }] } }"#);
+	});
+	let update = server.mock(|when, then| {
+		when.method(DELETE).path("/auth/update");
+		then.status(200);
+	});
+
+	let mut instance = CheckinAPI::from_token(String::new(), &server.base_url())
+		.with_graphql_path("/api/graphql")
+		.with_login_path("/auth/login")
+		.with_user_update_path("/auth/update");
+	instance.relogin("kiosk", "hunter2").unwrap();
+	assert_eq!(instance.get_tags_names(false).unwrap(), vec!["123"]);
+	instance.delete_user("scanner-1").unwrap();
+	login.assert();
+	tags.assert();
+	update.assert();
+}
+
+#[test]
+fn tag_exists() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST)
+			.path("/graphql")
+			.body_contains(r#""operationName":"TagsGet""#)
+			.body_contains(r#""only_current":false"#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "123" }, { "name": "lunch-day1" }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	assert!(instance.tag_exists("lunch-day1").unwrap());
+	assert!(!instance.tag_exists("lunch-day2").unwrap());
+	assert!(!instance.tag_exists("lunch").unwrap());
+}
+
+#[test]
+fn add_users_reports_each_account() {
+	let server = MockServer::start();
+	let created = server.mock(|when, then| {
+		when.method(PUT).path("/api/user/update").body_contains("username=scanner-1");
+		then.status(200);
+	});
+	server.mock(|when, then| {
+		when.method(PUT).path("/api/user/update").body_contains("username=scanner-2");
+		then.status(400).body("Username already exists");
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let accounts = [
+		("scanner-1".to_owned(), "password1".to_owned()),
+		("scanner-2".to_owned(), "password2".to_owned()),
+	];
+	let results = instance.add_users(&accounts);
+	created.assert();
+	assert_eq!(results.len(), 2);
+	assert!(results[0].is_ok());
+	match &results[1] {
+		Err(Error::Server { status, body }) => {
+			assert_eq!(*status, 400);
+			assert_eq!(body, "Username already exists");
+		},
+		other => panic!("Expected a server error, got {:?}", other),
+	}
+}
+
+#[test]
+fn auto_relogin_on_expired_session() {
+	const NEW_TOKEN: &str = "0000000000000000000000000000000000000000000000000000000000000001";
+	let server = MockServer::start();
+	let login = server.mock(|when, then| {
+		when.method(POST).path("/api/user/login").header("User-Agent", "hackgt-kiosk/1.2");
+		then.status(200)
+			.header("Set-Cookie", format!("auth={}; Path=/; HttpOnly", NEW_TOKEN));
+	});
+	let expired = server.mock(|when, then| {
+		when.method(POST).path("/graphql").header("Cookie", format!("auth={}", AUTH_TOKEN));
+		then.status(401);
+	});
+	let tags = server.mock(|when, then| {
+		when.method(POST).path("/graphql").header("Cookie", format!("auth={}", NEW_TOKEN));
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "123" }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url())
+		.with_user_agent("hackgt-kiosk/1.2");
+	let instance = Arc::new(AutoCheckinAPI::new(instance, "kiosk", "hunter2"));
+	// Two kiosk threads whose first requests both hit the expired session
+	let barrier = Arc::new(std::sync::Barrier::new(2));
+	let threads: Vec<_> = (0..2).map(|_| {
+		let instance = Arc::clone(&instance);
+		let barrier = Arc::clone(&barrier);
+		thread::spawn(move || instance.call(|api| {
+			if api.auth_token() == AUTH_TOKEN {
+				barrier.wait();
+			}
+			api.get_tags_names(false)
+		}))
+	}).collect();
+	for thread in threads {
+		assert_eq!(thread.join().unwrap().unwrap(), vec!["123"]);
+	}
+	expired.assert_hits(2);
+	login.assert_hits(1);
+	tags.assert_hits(2);
+	assert_eq!(instance.auth_token(), NEW_TOKEN);
+}
+
+#[test]
+fn iter_users_is_lazy() {
+	let server = MockServer::start();
+	let first_page = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"UsersGet""#).body_contains(r#""pagination_token":null"#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "users": [{{ "user": {}, "tags": [] }}] }} }}"#,
+				user_data().replacen("{", r#"{ "pagination_token": "7dd00021-89fd-49f1-9c17-bd0ba7dcf97e","#, 1)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let mut users = instance.iter_users();
+	first_page.assert_hits(0);
+	assert_eq!(users.next().unwrap().unwrap().name, "George P. Burdell");
+	// A short page is the last one
+	assert!(users.next().is_none());
+	first_page.assert_hits(1);
+}
+
+#[test]
+fn check_in_team() {
+	let server = MockServer::start();
+	let member = user_data().replacen("{", r#"{ "pagination_token": "1", "team": { "id": "team-1" },"#, 1);
+	let other = user_data()
+		.replacen("{", r#"{ "pagination_token": "2", "team": { "id": "team-2" },"#, 1)
+		.replace(USER_ID, "c1b4b2a0-0000-4000-8000-000000000000");
+	let users = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"UsersGet""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(r#"{{ "data": {{ "users": [{{ "user": {}, "tags": [] }}, {{ "user": {}, "tags": [] }}] }} }}"#, member, other));
+	});
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#, user_data(), tag_data("123", true)));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let results = instance.check_in_team("team-1", "123").unwrap();
+	users.assert_hits(1);
+	check_in.assert_hits(1);
+	assert_eq!(results.len(), 1);
+	assert_eq!(results[0].0, USER_ID);
+	assert!(results[0].1.as_ref().unwrap().0);
+
+	assert!(matches!(instance.check_in_team("team-3", "123"), Err(Error::Message("No users found on team"))));
+}
+
+#[test]
+#[cfg(feature = "chrono")]
+fn server_time() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(httpmock::Method::HEAD).path("/graphql");
+		then.status(405).header("Date", "Sat, 15 Feb 2020 18:00:00 GMT");
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	assert_eq!(instance.server_time().unwrap().timestamp(), 1581789600);
+}
+
+#[test]
+fn concurrent_check_ins() {
+	fn assert_send_sync<T: Send + Sync>() {}
+	assert_send_sync::<CheckinAPI>();
+	assert_send_sync::<AutoCheckinAPI>();
+
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("123", true)
+			));
+	});
+
+	let instance = Arc::new(CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url()));
+	let threads: Vec<_> = (0..8).map(|_| {
+		let instance = Arc::clone(&instance);
+		thread::spawn(move || instance.check_in(USER_ID, "123").map(|(success, _, _)| success))
+	}).collect();
+	for thread in threads {
+		assert!(thread.join().unwrap().unwrap());
+	}
+	check_in.assert_hits(8);
+}
+
+#[test]
+fn offline_queue_keeps_unsent_check_ins() {
+	use hackgt_nfc::offline::OfflineQueue;
+
+	let server = MockServer::start();
+	let mocks: Vec<_> = ["123", "456"].iter().map(|tag| server.mock(|when, then| {
+		when.method(POST).path("/graphql")
+			.body_contains(r#""operationName":"CheckInTag""#)
+			.body_contains(USER_ID)
+			.body_contains(format!(r#""tag":"{}""#, tag));
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data(tag, true)
+			));
+	})).collect();
+
+	// Each check-in goes to the tag it was queued for
+	let queue = OfflineQueue::new();
+	queue.push(USER_ID, "123");
+	queue.push(USER_ID, "456");
+	let pending = queue.pending();
+
+	// Nothing is listening here so every check-in fails with a network error
+	let offline = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), "http://127.0.0.1:1");
+	assert!(queue.flush(&offline).is_empty());
+	// Requeued check-ins keep the time they were first queued at
+	assert_eq!(queue.pending(), pending);
+
+	let online = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let results = queue.flush(&online);
+	assert_eq!(results.len(), 2);
+	assert!(results.iter().all(|(uuid, result)| uuid == USER_ID && result.is_ok()));
+	assert!(queue.is_empty());
+	for mock in &mocks {
+		mock.assert_hits(1);
+	}
+
+	queue.push("00000000-0000-0000-0000-000000000000", "123");
+	let discarded = queue.clear();
+	assert_eq!(discarded.len(), 1);
+	assert_eq!(discarded[0].uuid, "00000000-0000-0000-0000-000000000000");
+	assert!(queue.is_empty());
+}
+
+#[test]
+fn offline_queue_flush_concurrent() {
+	use hackgt_nfc::offline::OfflineQueue;
+
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("123", true)
+			));
+	});
+
+	let queue = OfflineQueue::new();
+	let uuids: Vec<String> = (0..20).map(|i| format!("00000000-0000-0000-0000-{:012}", i % 12)).collect();
+	for uuid in &uuids {
+		queue.push(uuid, "123");
+	}
+
+	let offline = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), "http://127.0.0.1:1");
+	let report = queue.flush_concurrent(&offline, 4);
+	assert_eq!((report.succeeded, report.rejected, report.requeued), (0, 0, 20));
+	assert_eq!(queue.len(), 20);
+
+	let online = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let report = queue.flush_concurrent(&online, 4);
+	assert_eq!((report.succeeded, report.rejected, report.requeued), (20, 0, 0));
+	// Results come back in queue order
+	assert!(report.results.iter().map(|(uuid, _)| uuid).eq(uuids.iter()));
+	assert!(queue.is_empty());
+	check_in.assert_hits(20);
+}
+
+#[test]
+fn check_in_outcomes() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("123", false)
+			));
+	});
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains("00000000-0000-0000-0000-000000000000");
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "check_in": null } }"#);
+	});
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"TagsGet""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "123" }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	assert!(matches!(instance.check_in_outcome(USER_ID, "123").unwrap(), CheckInOutcome::AlreadyCheckedIn(_)));
+	assert!(matches!(
+		instance.check_in_outcome("00000000-0000-0000-0000-000000000000", "123").unwrap(),
+		CheckInOutcome::UnknownUser
+	));
+	assert!(matches!(
+		instance.check_in("00000000-0000-0000-0000-000000000000", "123"),
+		Err(Error::Message("Invalid user ID on badge"))
+	));
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn session_fallback_tags() {
+	use hackgt_nfc::session::{ CheckinSession, ScanResult };
+
+	let server = MockServer::start();
+	let lunch = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(r#""tag":"lunch""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "check_in": null } }"#);
+	});
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"TagsGet""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tags": [{ "name": "lunch-sat" }] } }"#);
+	});
+	let lunch_sat = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(r#""tag":"lunch-sat""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("lunch-sat", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = CheckinSession::new(instance, "lunch").with_fallback_tags(vec!["lunch-sat".to_owned()]);
+	match session.scan(USER_ID).unwrap() {
+		ScanResult::CheckedIn(check_in) => {
+			assert_eq!(check_in.tag, "lunch-sat");
+			assert!(check_in.result.0);
+		},
+		other => panic!("Expected a check-in, got {:?}", other),
+	}
+	lunch.assert();
+	lunch_sat.assert();
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn session_sync_while_shared() {
+	use hackgt_nfc::offline::AccessCache;
+	use hackgt_nfc::session::{ CheckinSession, ScanResult };
+
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("door", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let mut session = CheckinSession::new(instance, "door");
+	session.set_access_cache(Some(AccessCache::from_uuids(vec![USER_ID.to_owned()])));
+	let session = Arc::new(session);
+
+	// `checkin_mode` takes an `Arc` so the caller can keep its own handle to the running session
+	let _: fn(Arc<CheckinSession>, fn(ScanResult)) -> hackgt_nfc::nfc::ReaderLoop = CheckinSession::checkin_mode;
+	// A reader thread holds the session like `checkin_mode` does
+	let reader = Arc::clone(&session);
+	let scan = thread::spawn(move || reader.scan(USER_ID)).join().unwrap();
+	assert!(matches!(scan, Ok(ScanResult::Queued)));
+	check_in.assert_hits(0);
+
+	let results = session.sync();
+	assert_eq!(results.len(), 1);
+	assert!(results[0].1.as_ref().unwrap().0);
+	assert!(session.queue().is_empty());
+	check_in.assert();
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn session_cooldown_while_checking_in() {
+	use hackgt_nfc::session::{ CheckinSession, ScanResult };
+	use std::time::Duration;
+
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.delay(Duration::from_millis(300))
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("door", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = Arc::new(CheckinSession::new(instance, "door").with_scan_cooldown(Duration::from_secs(60)));
+
+	// The same badge tapped on two readers while the first check-in is still being sent
+	let scans: Vec<_> = (0..2).map(|_| {
+		let reader = Arc::clone(&session);
+		thread::spawn(move || reader.scan(USER_ID).unwrap())
+	}).collect();
+	let results: Vec<ScanResult> = scans.into_iter().map(|scan| scan.join().unwrap()).collect();
+	assert_eq!(results.iter().filter(|result| matches!(result, ScanResult::CheckedIn(_))).count(), 1);
+	assert_eq!(results.iter().filter(|result| matches!(result, ScanResult::Cooldown)).count(), 1);
+	check_in.assert_hits(1);
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn session_drain_while_scanning() {
+	use hackgt_nfc::session::{ CheckinSession, ScanResult };
+	use std::time::Duration;
+
+	let server = MockServer::start();
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(USER_ID);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.delay(Duration::from_secs(1))
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("door", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = Arc::new(CheckinSession::new(instance, "door"));
+	session.queue().push(USER_ID, "door");
+
+	// A reader thread is in the middle of an online check-in when the shift ends
+	let reader = Arc::clone(&session);
+	let scan = thread::spawn(move || reader.scan(USER_ID));
+	thread::sleep(Duration::from_millis(200));
+
+	let report = session.drain(Duration::from_millis(50));
+	assert_eq!(report.in_progress, 1);
+	assert_eq!(report.flushed.len(), 1);
+	assert!(report.flushed[0].1.as_ref().unwrap().0);
+	assert!(report.remaining.is_empty());
+
+	// The scan that was already running still finishes, but new ones are turned away
+	assert!(matches!(scan.join().unwrap(), Ok(ScanResult::CheckedIn(_))));
+	assert!(session.scan(USER_ID).is_err());
+	let report = session.drain(Duration::from_millis(50));
+	assert_eq!(report.in_progress, 0);
+	assert!(report.flushed.is_empty());
+	check_in.assert_hits(2);
+}
+
+#[test]
+fn check_in_with_override() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data().replace(r#""confirmed": true"#, r#""confirmed": false"#), tag_data("123", true)
+			));
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	assert!(matches!(instance.check_in(USER_ID, "123"), Err(Error::Message("User not accepted and confirmed"))));
+	assert!(matches!(
+		instance.check_in_with_override(USER_ID, "123", " "),
+		Err(Error::Message("An override reason is required"))
+	));
+	let check_in = instance.check_in_with_override(USER_ID, "123", "Speaker").unwrap();
+	assert!(check_in.overridden);
+	assert!(check_in.result.0);
+	assert_eq!(check_in.reason, "Speaker");
+}
+
+#[cfg(feature = "nfc")]
+#[test]
+fn session_eligibility_policy() {
+	use hackgt_nfc::api::Eligibility;
+	use hackgt_nfc::session::{ CheckinSession, ScanResult };
+	use std::collections::HashMap;
+
+	let unconfirmed = user_data().replace(r#""confirmed": true"#, r#""confirmed": false"#);
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"UserGet""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(r#"{{ "data": {{ "user": {{ "user": {}, "tags": [] }} }} }}"#, unconfirmed));
+	});
+	let check_in = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#).body_contains(r#""tag":"registration""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				unconfirmed, tag_data("registration", true)
+			));
+	});
+
+	let mut policy = HashMap::new();
+	policy.insert("registration".to_owned(), Eligibility::RequireAcceptedOnly);
+	policy.insert("lunch".to_owned(), Eligibility::RequireAcceptedAndConfirmed);
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = CheckinSession::new(instance, "registration").with_eligibility_policy(policy.clone());
+	assert!(matches!(session.scan(USER_ID), Ok(ScanResult::CheckedIn(_))));
+
+	// Turned away without sending the check-in
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = CheckinSession::new(instance, "lunch").with_eligibility_policy(policy.clone());
+	assert!(session.scan(USER_ID).is_err());
+	check_in.assert_hits(1);
+
+	// A fallback tag whose rule lets the user in is used instead
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let session = CheckinSession::new(instance, "lunch")
+		.with_eligibility_policy(policy)
+		.with_fallback_tags(vec!["registration".to_owned()]);
+	match session.scan(USER_ID) {
+		Ok(ScanResult::CheckedIn(check_in)) => assert_eq!(check_in.tag, "registration"),
+		other => panic!("Expected a check-in, got {:?}", other),
+	}
+	check_in.assert_hits(2);
+}
+
+#[test]
+fn check_in_and_count() {
+	let server = MockServer::start();
+	server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"CheckInTag""#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(format!(
+				r#"{{ "data": {{ "check_in": {{ "user": {}, "tags": [{}] }} }} }}"#,
+				user_data(), tag_data("123", true)
+			));
+	});
+	let counts = server.mock(|when, then| {
+		when.method(POST).path("/graphql").body_contains(r#""operationName":"TagCounts""#).body_contains(r#""tags":["123"]"#);
+		then.status(200)
+			.header("Content-Type", "application/json")
+			.body(r#"{ "data": { "tag_counts": [{ "name": "123", "count": 42 }] } }"#);
+	});
+
+	let instance = CheckinAPI::from_token(AUTH_TOKEN.to_owned(), &server.base_url());
+	let ((success, _, _), count) = instance.check_in_and_count(USER_ID, "123").unwrap();
+	assert!(success);
+	assert_eq!(count, 42);
+	counts.assert();
+}